@@ -0,0 +1,57 @@
+// compares the line by line split with the mmap fast path on a synthetic book
+// run with `cargo bench --bench split` , size in MB via `SPLIT_BENCH_MB` (default 256)
+#[allow(dead_code)]
+#[path = "../src/processor/splitter.rs"]
+mod splitter;
+
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+fn write_book(path: &Path, size: usize) -> std::io::Result<()> {
+	let mut out = BufWriter::new(fs::File::create(path)?);
+	let line = "这是一行用于基准测试的正文内容 , the quick brown fox jumps over the lazy dog\n";
+	let mut written = 0;
+	let mut chapter = 0;
+	while written < size {
+		writeln!(out, "{} 第{}章", splitter::DELIMITER, chapter)?;
+		for _ in 0..200 {
+			out.write_all(line.as_bytes())?;
+			written += line.len();
+		}
+		chapter += 1;
+	}
+	writeln!(out, "{} end", splitter::DELIMITER)?;
+	out.flush()
+}
+
+fn run(name: &str, out_dir: &Path, f: impl Fn(&Path) -> anyhow::Result<usize>) -> anyhow::Result<()> {
+	let _ = fs::remove_dir_all(out_dir);
+	fs::create_dir_all(out_dir)?;
+	let start = Instant::now();
+	let chapters = f(out_dir)?;
+	println!("{:<10} {:>8} chapters in {:?}", name, chapters, start.elapsed());
+	Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+	let mb: usize = std::env::var("SPLIT_BENCH_MB").ok().and_then(|v| v.parse().ok()).unwrap_or(256);
+	let root = std::env::temp_dir().join("xreader_split_bench");
+	fs::create_dir_all(&root)?;
+	let source = root.join("bench.txt");
+	write_book(&source, mb * 1024 * 1024)?;
+	println!("source: {} MB", mb);
+
+	run("buffered", &root.join("buffered"), |out| splitter::split_buffered(&source, out, "bench", "txt", &splitter::SplitOptions::until(i32::MAX), &splitter::Progress::default()))?;
+	run("mmap", &root.join("mmap"), |out| splitter::split_mmap(&source, out, "bench", "txt", &splitter::SplitOptions::until(i32::MAX), &splitter::Progress::default()))?;
+
+	for entry in fs::read_dir(root.join("buffered"))? {
+		let entry = entry?;
+		let other = root.join("mmap").join(entry.file_name());
+		assert_eq!(fs::read(entry.path())?, fs::read(&other)?, "{:?} differs", other);
+	}
+
+	fs::remove_dir_all(&root)?;
+	Ok(())
+}
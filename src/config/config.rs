@@ -1,14 +1,44 @@
 use config::{Config,ConfigError,Environment,File};
-use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::{Path,PathBuf};
-use log::{debug,error,info,warn};
-use anyhow::{Context,Result};
+use std::time::Duration;
+use rand::Rng;
+use anyhow::{bail,Context,Result};
+use redis::{ClientTlsConfig,ConnectionInfo,IntoConnectionInfo,RedisConnectionInfo,TlsCertificates,TlsMode};
+use redis::aio::ConnectionManager;
+use redis::cluster::ClusterClient;
+use redis::sentinel::SentinelNodeConnectionInfo;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use tokio::sync::OnceCell;
+use log::{info,warn};
+use crate::model::keys::Keys;
+use super::connection::{cluster_masters,node_info,RedisConn,SentinelResolver,SentinelSlot};
+
+// name RedisJSON reports in MODULE LIST
+const REDIS_JSON_MODULE:&str = "ReJSON";
+
 #[allow(dead_code)]
 #[derive(Debug,serde::Deserialize)]
 pub struct Settings{
     pub file_processing:FileProcessConfig,
     pub redis:RedisConfig,
     pub watcher:WatcherConfig,
+    #[serde(default)]
+    pub store:StoreConfig,
+}
+
+// where books are looked up , queues , locks and job state stay in redis either way
+#[derive(Debug,Clone,Default,serde::Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum StoreConfig{
+    // the `book:<id>` keys , kept as `redis.storage` says
+    #[default]
+    Redis,
+    // a sqlite file , created when missing
+    Sqlite{ path:String },
+    // nothing survives a restart
+    Memory,
 }
 
 #[allow(dead_code)]
@@ -17,14 +47,115 @@ pub struct FileProcessConfig{
     pub input_dir:String,
     pub output_dir:String,
     pub supported_ext:Vec<String>,
+    // source files at least this many bytes are split with mmap
+    #[serde(default = "default_large_file_threshold")]
+    pub large_file_threshold:u64,
+    #[serde(default)]
+    pub cancel_policy:CancelPolicy,
+    // output of a book whose source was removed is moved here , kept in place when missing
+    #[serde(default)]
+    pub archive_dir:Option<String>,
+}
+
+// what happens to chapters already written when a job is cancelled
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CancelPolicy{
+    // leave them , a later run skips chapters that exist
+    #[default]
+    Keep,
+    // delete the chapter files the cancelled run created
+    Remove,
+}
+
+fn default_large_file_threshold()->u64{
+    crate::processor::splitter::DEFAULT_LARGE_FILE_THRESHOLD
 }
 
 #[allow(dead_code)]
 #[derive(Debug,serde::Deserialize)]
 pub struct RedisConfig{
+    // a single node , not used with `sentinel` or `cluster`
+    #[serde(default)]
     pub url:String,
+    // ACL user , `pass` alone authenticates as the default user
+    #[serde(default)]
+    pub username:Option<String>,
     pub pass:Option<String>,
+    // namespace of every key , channel and stream , `staging` gives `staging:book:42` , empty for none
     pub key_prefix:String,
+    // every node must then be a `rediss://` url
+    #[serde(default)]
+    pub tls:Option<TlsConfig>,
+    // shared multiplexed connections , blocking reads and pubsub still open their own
+    #[serde(default = "default_pool_size")]
+    pub pool_size:usize,
+    // how books are stored , `auto` uses RedisJSON when the server has it
+    #[serde(default)]
+    pub storage:BookStorage,
+    #[serde(default)]
+    pub sentinel:Option<SentinelConfig>,
+    #[serde(default)]
+    pub cluster:Option<ClusterConfig>,
+}
+
+// how a book is kept under `book:<id>`
+#[derive(Debug,Clone,Copy,Default,PartialEq,Eq,serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookStorage{
+    // json when MODULE LIST shows RedisJSON , string otherwise
+    #[default]
+    Auto,
+    // a RedisJSON document
+    Json,
+    // the book serialized to json in a plain string
+    String,
+    // one hash field per book field , each value json encoded
+    Hash,
+}
+
+// the master is looked up through the sentinels , and again after a failover
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+pub struct SentinelConfig{
+    pub master_name:String,
+    // `redis://host:26379` of each sentinel
+    pub nodes:Vec<String>,
+}
+
+// the rest of the cluster is discovered from the seed nodes
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+pub struct ClusterConfig{
+    pub nodes:Vec<String>,
+}
+
+fn default_pool_size()->usize{
+    4
+}
+
+// PEM files , the system roots are trusted without `ca_file` , a client certificate
+// needs both `cert_file` and `key_file`
+#[allow(dead_code)]
+#[derive(Debug,Clone,Default,serde::Deserialize)]
+#[serde(default)]
+pub struct TlsConfig{
+    pub ca_file:Option<String>,
+    pub cert_file:Option<String>,
+    pub key_file:Option<String>,
+}
+
+impl TlsConfig{
+    // the files are read once , at startup
+    pub fn certificates(&self)->Result<TlsCertificates>{
+        let read = |path:&String| std::fs::read(path).with_context(|| format!("Failed to read {:?}",path));
+        let client_tls = match (&self.cert_file,&self.key_file){
+            (Some(cert),Some(key)) => Some(ClientTlsConfig{ client_cert:read(cert)?, client_key:read(key)? }),
+            (None,None) => None,
+            _ => bail!("redis tls needs both cert_file and key_file"),
+        };
+        Ok(TlsCertificates{ client_tls, root_cert:self.ca_file.as_ref().map(read).transpose()? })
+    }
 }
 
 #[allow(dead_code)]
@@ -32,6 +163,191 @@ pub struct RedisConfig{
 pub struct WatcherConfig{
     pub recursive:bool,
     pub concurrent_limit:usize,
+    // tasks waiting for a free worker before producers block
+    #[serde(default = "default_queue_size")]
+    pub queue_size:usize,
+    // a reserved queue item not acked or extended within this many seconds is requeued
+    #[serde(default = "default_visibility_timeout")]
+    pub visibility_timeout:u64,
+    // read tasks from a redis stream consumer group as well , off when missing
+    #[serde(default)]
+    pub stream:Option<StreamConfig>,
+    #[serde(default)]
+    pub events:EventConfig,
+    #[serde(default)]
+    pub retry:RetryPolicy,
+    // seconds a per-book lock lives without renewal , renewed while the book is split
+    #[serde(default = "default_lock_ttl")]
+    pub lock_ttl:u64,
+    // seconds between reconciliation passes over redis , input_dir and output_dir
+    #[serde(default = "default_check_interval")]
+    pub check_interval:u64,
+    #[serde(default)]
+    pub upload:UploadConfig,
+    #[serde(default)]
+    pub backfill:BackfillConfig,
+}
+
+// startup pass over every stored book
+// keys are read with SCAN `batch` at a time and at most `rate` books per second are queued ,
+// at least 1 , the cursor is kept in redis so a restart resumes where it stopped
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+#[serde(default)]
+pub struct BackfillConfig{
+    pub batch:usize,
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub rate:u32,
+}
+
+fn deserialize_rate<'de,D:serde::Deserializer<'de>>(deserializer:D)->std::result::Result<u32,D::Error>{
+    let rate = <u32 as serde::Deserialize>::deserialize(deserializer)?;
+    if rate == 0{
+        return Err(serde::de::Error::custom("backfill rate must be at least 1"));
+    }
+    Ok(rate)
+}
+
+impl Default for BackfillConfig{
+    fn default()->Self{
+        Self{
+            batch: 100,
+            rate: 20,
+        }
+    }
+}
+
+// when a file written into input_dir counts as complete
+// its size and mtime stayed the same for `quiet_period` seconds , a `<name><done_suffix>`
+// sidecar appeared , or it was renamed from `<name><part_suffix>`
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+#[serde(default)]
+pub struct UploadConfig{
+    pub quiet_period:u64,
+    pub done_suffix:String,
+    pub part_suffix:String,
+}
+
+impl Default for UploadConfig{
+    fn default()->Self{
+        Self{
+            quiet_period: 10,
+            done_suffix: ".done".to_string(),
+            part_suffix: ".part".to_string(),
+        }
+    }
+}
+
+// how often a failed book is split again before it goes to the dead letter list
+// the n-th retry waits `base_delay * 2^(n-1)` ms , capped at `max_delay` ,
+// then moved by up to `jitter` of itself either way
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy{
+    // attempts in total , including the first one
+    pub max_attempts:u32,
+    pub base_delay:u64,
+    pub max_delay:u64,
+    pub jitter:f64,
+}
+
+impl Default for RetryPolicy{
+    fn default()->Self{
+        Self{
+            max_attempts: 3,
+            base_delay: 1000,
+            max_delay: 60_000,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy{
+    // backoff between attempts to reach redis again after an error , 1s , 2s , 4s ... up to a minute
+    pub const RECONNECT:RetryPolicy = RetryPolicy{ max_attempts: u32::MAX, base_delay: 1000, max_delay: 60_000, jitter: 0.0 };
+
+    // wait before the attempt following failed attempt number `attempt` ( from 1 )
+    pub fn delay(&self,attempt:u32)->Duration{
+        let exp = self.base_delay.saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+        let capped = exp.min(self.max_delay) as f64;
+        let jitter = self.jitter.clamp(0.0,1.0);
+        let factor = if jitter > 0.0 { rand::thread_rng().gen_range(1.0-jitter..=1.0+jitter) } else { 1.0 };
+        Duration::from_millis((capped*factor) as u64)
+    }
+}
+
+// where job results are published , either or both may be set , both go under `key_prefix`
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+pub struct EventConfig{
+    #[serde(default = "default_event_channel")]
+    pub channel:Option<String>,
+    #[serde(default)]
+    pub stream:Option<String>,
+    // approximate cap on the result stream length
+    #[serde(default)]
+    pub stream_maxlen:Option<usize>,
+}
+
+impl Default for EventConfig{
+    fn default()->Self{
+        Self{
+            channel: default_event_channel(),
+            stream: None,
+            stream_maxlen: None,
+        }
+    }
+}
+
+fn default_event_channel()->Option<String>{
+    Some(crate::model::book::CHANNEL_PSB_BOOK_RESULT.to_string())
+}
+
+#[allow(dead_code)]
+#[derive(Debug,Clone,serde::Deserialize)]
+pub struct StreamConfig{
+    // put under `key_prefix` like every other key
+    #[serde(default = "default_stream_key")]
+    pub key:String,
+    #[serde(default = "default_stream_group")]
+    pub group:String,
+    // defaults to the hostname , must differ between instances
+    pub consumer:Option<String>,
+    // seconds an entry may stay pending before another consumer claims it
+    #[serde(default = "default_visibility_timeout")]
+    pub claim_idle:u64,
+}
+
+impl StreamConfig{
+    pub fn consumer_name(&self)->String{
+        self.consumer.clone().unwrap_or_else(|| gethostname::gethostname().to_string_lossy().to_string())
+    }
+}
+
+fn default_stream_key()->String{
+    "stream:book:task".to_string()
+}
+
+fn default_stream_group()->String{
+    "cutter".to_string()
+}
+
+fn default_visibility_timeout()->u64{
+    600
+}
+
+fn default_check_interval()->u64{
+    120
+}
+
+fn default_lock_ttl()->u64{
+    30
+}
+
+fn default_queue_size()->usize{
+    64
 }
 
 impl Settings{
@@ -56,6 +372,17 @@ impl FileProcessConfig{
         self.normalize_path(&self.output_dir)
     }
 
+    // created when missing
+    pub fn archive_path(&self)->Result<Option<PathBuf>>{
+        match &self.archive_dir{
+            Some(dir) => {
+                std::fs::create_dir_all(dir).context("Faild to create archive dir")?;
+                Ok(Some(self.normalize_path(dir)?))
+            }
+            None => Ok(None),
+        }
+    }
+
     fn normalize_path(&self,path:&str)->Result<PathBuf>{
         let path = Path::new(path);
         let full_path = std::env::current_dir()?.join(path);
@@ -68,32 +395,325 @@ impl FileProcessConfig{
     }
 }
 
+// where connections go
+enum Topology{
+    Single(redis::Client),
+    Sentinel(Arc<SentinelResolver>),
+    // seeds are kept to reach single nodes , for pubsub and SCAN
+    Cluster{ client:ClusterClient, seeds:Vec<ConnectionInfo> },
+}
+
+// credentials and certificates every connection is opened with
+#[derive(Clone,Default)]
+struct Auth{
+    username:Option<String>,
+    pass:Option<String>,
+    tls:Option<TlsCertificates>,
+}
+
+impl Auth{
+    // a password in the url is kept when `pass` is missing , the same for the user
+    fn info(&self,url:&str)->Result<ConnectionInfo>{
+        let mut info = url.into_connection_info().with_context(|| format!("invalid redis url {:?}",url))?;
+        if self.username.is_some(){
+            info.redis.username = self.username.clone();
+        }
+        if self.pass.is_some(){
+            info.redis.password = self.pass.clone();
+        }
+        self.secure(info)
+    }
+
+    // the certificates attached , fails for an address that is not `rediss://`
+    fn secure(&self,info:ConnectionInfo)->Result<ConnectionInfo>{
+        match self.tls{
+            Some(ref certs) => {
+                let client = redis::Client::build_with_tls(info,certs.clone()).context("redis tls")?;
+                Ok(client.get_connection_info().clone())
+            }
+            None => Ok(info),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub struct RedisClient{
-    pub client:redis::Client,
+    topology:Topology,
     pub pass:Option<String>,
     pub key_prefix:String,
+    keys:Keys,
+    storage:BookStorage,
+    // `storage` with `auto` resolved , found out on first use
+    resolved:OnceCell<BookStorage>,
+    pool_size:usize,
+    // opened on first use , each one reconnects by itself after a dropped connection
+    pool:OnceCell<Vec<RedisConn>>,
+    next:AtomicUsize,
+    // one per node holding keys , opened by the first SCAN
+    nodes:OnceCell<Vec<RedisConn>>,
 }
 
+#[allow(dead_code)]
 impl RedisClient{
     pub fn new(url:&str , pass: Option<String>,key_prefix:String)->Result<Self>{
-        Ok(Self{
-         client: redis::Client::open(url)?,
-         pass,   
-         key_prefix,
-        })
+        Self::single(url,Auth{ pass, ..Default::default() },key_prefix)
+    }
+
+    // the credentials go into the connection info so every reconnect authenticates again
+    fn single(url:&str,auth:Auth,key_prefix:String)->Result<Self>{
+        let client = redis::Client::open(auth.info(url)?)?;
+        Ok(Self::with_topology(Topology::Single(client),auth.pass,key_prefix))
+    }
+
+    // the sentinels get the certificates but not the credentials of the master
+    fn sentinel(master_name:&str,nodes:&[String],auth:Auth,key_prefix:String)->Result<Self>{
+        let nodes = nodes.iter()
+            .map(|node| auth.secure(node.as_str().into_connection_info()?))
+            .collect::<Result<Vec<_>>>()?;
+        let master = SentinelNodeConnectionInfo{
+            tls_mode:auth.tls.as_ref().map(|_| TlsMode::Secure),
+            redis_connection_info:Some(RedisConnectionInfo{ username:auth.username.clone(), password:auth.pass.clone(), ..Default::default() }),
+        };
+        let resolver = SentinelResolver::new(nodes,master_name.to_string(),master,auth.tls.clone())?;
+        Ok(Self::with_topology(Topology::Sentinel(Arc::new(resolver)),auth.pass,key_prefix))
+    }
+
+    fn cluster(nodes:&[String],auth:Auth,key_prefix:String)->Result<Self>{
+        let seeds = nodes.iter().map(|node| auth.info(node)).collect::<Result<Vec<_>>>()?;
+        if seeds.is_empty(){
+            bail!("redis cluster needs at least one seed node");
+        }
+        let mut builder = ClusterClient::builder(seeds.clone());
+        if let Some(ref certs) = auth.tls{
+            builder = builder.certs(certs.clone());
+        }
+        Ok(Self::with_topology(Topology::Cluster{ client:builder.build()?, seeds },auth.pass,key_prefix))
+    }
+
+    // sentinel before cluster before a single url
+    pub fn from_config(config:&RedisConfig)->Result<Self>{
+        let auth = Auth{
+            username:config.username.clone(),
+            pass:config.pass.clone(),
+            tls:config.tls.as_ref().map(|tls| tls.certificates()).transpose()?,
+        };
+        let client = if let Some(ref sentinel) = config.sentinel{
+            Self::sentinel(&sentinel.master_name,&sentinel.nodes,auth,config.key_prefix.clone())?
+        }else if let Some(ref cluster) = config.cluster{
+            Self::cluster(&cluster.nodes,auth,config.key_prefix.clone())?
+        }else{
+            Self::single(&config.url,auth,config.key_prefix.clone())?
+        };
+        Ok(client.with_pool_size(config.pool_size).with_storage(config.storage))
     }
-    pub async  fn get_connection(&self)->Result<redis::aio::Connection,redis::RedisError>{
-        let mut conn = self.client.get_async_connection().await?;
-        if let Some(ref pass) = self.pass{ 
-            redis::cmd("AUTH").arg(pass).query_async(&mut conn).await?
+
+    fn with_topology(topology:Topology,pass:Option<String>,key_prefix:String)->Self{
+        let keys = Keys::new(&key_prefix,matches!(topology,Topology::Cluster{..}));
+        Self{
+            topology,
+            pass,
+            key_prefix,
+            keys,
+            storage:BookStorage::Auto,
+            resolved:OnceCell::new(),
+            pool_size:default_pool_size(),
+            pool:OnceCell::new(),
+            next:AtomicUsize::new(0),
+            nodes:OnceCell::new(),
+        }
+    }
+
+    pub fn with_pool_size(mut self,pool_size:usize)->Self{
+        self.pool_size = pool_size.max(1);
+        self
+    }
+
+    pub fn with_storage(mut self,storage:BookStorage)->Self{
+        self.storage = storage;
+        self
+    }
+
+    // asks the server once with `MODULE LIST` whether RedisJSON is loaded
+    // a server that refuses MODULE LIST needs `storage` set explicitly
+    pub async fn book_storage(&self)->Result<BookStorage,redis::RedisError>{
+        if self.storage != BookStorage::Auto{
+            return Ok(self.storage);
+        }
+        let storage = self.resolved.get_or_try_init(|| async{
+            let modules:Vec<HashMap<String,redis::Value>> = match redis::cmd("MODULE").arg("LIST").query_async(&mut self.get_manager().await?).await{
+                Ok(modules) => modules,
+                Err(e) => {
+                    warn!("MODULE LIST err , set redis.storage instead of auto: {:?}",e);
+                    return Err(e);
+                }
+            };
+            let json = modules.iter().any(|module| module.get("name")
+                .and_then(|name| redis::from_redis_value::<String>(name).ok())
+                .is_some_and(|name| name.eq_ignore_ascii_case(REDIS_JSON_MODULE)));
+            let storage = if json { BookStorage::Json } else { BookStorage::String };
+            info!("Book storage: {:?}",storage);
+            Ok(storage)
+        }).await?;
+        Ok(*storage)
+    }
+
+    pub fn is_cluster(&self)->bool{
+        matches!(self.topology,Topology::Cluster{..})
+    }
+
+    // names every key and channel , see `Keys`
+    pub fn keys(&self)->&Keys{
+        &self.keys
+    }
+
+    // a connection of its own , for blocking commands that would stall a shared one
+    pub async fn get_connection(&self)->Result<RedisConn,redis::RedisError>{
+        match self.topology{
+            Topology::Single(ref client) => Ok(RedisConn::Single(Box::new(ConnectionManager::new(client.clone()).await?))),
+            Topology::Sentinel(ref resolver) => Ok(RedisConn::Sentinel(Arc::new(SentinelSlot::open(resolver.clone()).await?))),
+            Topology::Cluster{ ref client, .. } => Ok(RedisConn::Cluster(client.get_async_connection().await?)),
+        }
+    }
+
+    // one of the shared connections , handed out round robin , cheap to clone
+    pub async fn get_manager(&self)->Result<RedisConn,redis::RedisError>{
+        let pool = self.pool.get_or_try_init(|| async{
+            let mut pool = Vec::with_capacity(self.pool_size);
+            for _ in 0..self.pool_size{
+                pool.push(self.get_connection().await?);
+            }
+            Ok::<_,redis::RedisError>(pool)
+        }).await?;
+        let i = self.next.fetch_add(1,Ordering::Relaxed) % pool.len();
+        Ok(pool[i].clone())
+    }
+
+    // published messages reach every node of a cluster , so any seed will do
+    pub async fn get_pubsub(&self)->Result<redis::aio::PubSub,redis::RedisError>{
+        let conn = match self.topology{
+            Topology::Single(ref client) => client.get_async_connection().await?,
+            Topology::Sentinel(ref resolver) => resolver.master().await?.get_async_connection().await?,
+            Topology::Cluster{ ref seeds, .. } => {
+                let mut last = None;
+                let mut found = None;
+                for seed in seeds.iter(){
+                    match redis::Client::open(seed.clone())?.get_async_connection().await{
+                        Ok(conn) => {
+                            found = Some(conn);
+                            break;
+                        }
+                        Err(e) => last = Some(e),
+                    }
+                }
+                match found{
+                    Some(conn) => conn,
+                    None => return Err(last.unwrap_or_else(|| (redis::ErrorKind::IoError,"no seed node").into())),
+                }
+            }
+        };
+        Ok(conn.into_pubsub())
+    }
+
+    // a connection to every node holding keys , SCAN only walks the node it is sent to
+    pub async fn scan_nodes(&self)->Result<Vec<RedisConn>,redis::RedisError>{
+        let seeds = match self.topology{
+            Topology::Cluster{ ref seeds, .. } => seeds,
+            _ => return Ok(vec![self.get_manager().await?]),
+        };
+        let nodes = self.nodes.get_or_try_init(|| async{
+            let mut nodes = Vec::new();
+            for (host,port) in cluster_masters(&mut self.get_manager().await?).await?{
+                let client = redis::Client::open(node_info(&seeds[0],host,port))?;
+                nodes.push(RedisConn::Single(Box::new(ConnectionManager::new(client).await?)));
+            }
+            Ok::<_,redis::RedisError>(nodes)
+        }).await?;
+        Ok(nodes.clone())
+    }
+}
+
+impl std::fmt::Debug for Topology{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self{
+            Topology::Single(client) => write!(f, "Single({:?})", client.get_connection_info().addr.to_string()),
+            Topology::Sentinel(resolver) => write!(f, "Sentinel({:?})", resolver.master_name()),
+            Topology::Cluster{ seeds, .. } => write!(f, "Cluster({:?})", seeds.iter().map(|seed| seed.addr.to_string()).collect::<Vec<_>>()),
         }
-        Ok(conn)
     }
 }
 
 impl std::fmt::Debug for RedisClient{
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "RedisClient {{ client: {:?}, pass: {:?}, key_prefix: {:?} }}", self.client, self.pass, self.key_prefix)
+        // the password is never printed , only whether one is set
+        let pass = self.pass.as_ref().map(|_| "***");
+        write!(f, "RedisClient {{ topology: {:?}, pass: {:?}, key_prefix: {:?}, pool_size: {:?} }}", self.topology, pass, self.key_prefix, self.pool_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backfill_rate_must_be_positive(){
+        let config:BackfillConfig = serde_json::from_value(serde_json::json!({ "rate": 5 })).unwrap();
+        assert_eq!((config.batch,config.rate),(100,5));
+        assert!(serde_json::from_value::<BackfillConfig>(serde_json::json!({ "rate": 0 })).is_err());
+    }
+
+    fn policy(jitter:f64)->RetryPolicy{
+        RetryPolicy{ max_attempts: 5, base_delay: 1000, max_delay: 10_000, jitter }
+    }
+
+    #[test]
+    fn reconnect_waits_up_to_a_minute(){
+        let delays:Vec<u64> = [1,2,3,7,8,1000].iter().map(|&retry| RetryPolicy::RECONNECT.delay(retry).as_secs()).collect();
+        assert_eq!(delays,vec![1,2,4,60,60,60]);
+    }
+
+    #[test]
+    fn delay_doubles_up_to_max_delay(){
+        let policy = policy(0.0);
+        let delays:Vec<u64> = (1..=6).map(|attempt| policy.delay(attempt).as_millis() as u64).collect();
+        assert_eq!(delays,vec![1000,2000,4000,8000,10_000,10_000]);
+        // attempt 0 is read as the first one
+        assert_eq!(policy.delay(0),Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn delay_does_not_overflow_on_large_attempts(){
+        let policy = policy(0.0);
+        for attempt in [33,64,100,u32::MAX]{
+            assert_eq!(policy.delay(attempt),Duration::from_millis(10_000),"attempt {}",attempt);
+        }
+        let unbounded = RetryPolicy{ max_delay: u64::MAX, ..policy };
+        assert_eq!(unbounded.delay(u32::MAX),Duration::from_millis(1000u64 << 32));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_share_of_the_delay(){
+        let policy = policy(0.2);
+        for attempt in 1..=6{
+            let capped = 1000u64.saturating_mul(1 << (attempt-1)).min(10_000);
+            for _ in 0..50{
+                let delay = policy.delay(attempt).as_millis() as u64;
+                assert!(delay >= capped*8/10 && delay <= capped*12/10,"attempt {} delay {}",attempt,delay);
+            }
+        }
+    }
+
+    #[test]
+    fn jitter_is_clamped(){
+        // a jitter above 1 could go below zero , it is read as 1
+        for _ in 0..50{
+            assert!(policy(5.0).delay(4) <= Duration::from_millis(16_000));
+        }
+        assert_eq!(policy(-1.0).delay(1),Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn zero_base_delay_retries_at_once(){
+        let policy = RetryPolicy{ base_delay: 0, ..policy(0.2) };
+        assert_eq!(policy.delay(3),Duration::ZERO);
     }
 }
@@ -0,0 +1,160 @@
+use log::{info, warn};
+use redis::aio::{ConnectionLike, ConnectionManager};
+use redis::cluster_async::ClusterConnection;
+use redis::sentinel::{Sentinel, SentinelNodeConnectionInfo};
+use redis::{Cmd, ConnectionAddr, ConnectionInfo, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, TlsCertificates, Value};
+use std::sync::{Arc, Mutex};
+
+// a connection to whatever `RedisConfig` points at , cheap to clone
+// commands go through the pool of the client it came from unless it was opened on its own
+#[derive(Clone)]
+pub enum RedisConn{
+	Single(Box<ConnectionManager>),
+	Sentinel(Arc<SentinelSlot>),
+	Cluster(ClusterConnection),
+}
+
+// finds the current master of a sentinel group
+pub struct SentinelResolver{
+	sentinel:tokio::sync::Mutex<Sentinel>,
+	master_name:String,
+	node:SentinelNodeConnectionInfo,
+	// the sentinels only report host and port , the certificates are added to the master they name
+	tls:Option<TlsCertificates>,
+}
+
+impl SentinelResolver{
+	pub fn new(nodes:Vec<ConnectionInfo>, master_name:String, node:SentinelNodeConnectionInfo, tls:Option<TlsCertificates>)->RedisResult<Self>{
+		Ok(Self{ sentinel:tokio::sync::Mutex::new(Sentinel::build(nodes)?), master_name, node, tls })
+	}
+
+	pub async fn master(&self)->RedisResult<redis::Client>{
+		let client = self.sentinel.lock().await.async_master_for(&self.master_name, Some(&self.node)).await?;
+		match self.tls{
+			Some(ref certs) => redis::Client::build_with_tls(client.get_connection_info().clone(), certs.clone()),
+			None => Ok(client),
+		}
+	}
+
+	pub fn master_name(&self)->&str{
+		&self.master_name
+	}
+}
+
+// a connection to the sentinel master , opened again on the new master after a failover
+pub struct SentinelSlot{
+	resolver:Arc<SentinelResolver>,
+	manager:Mutex<ConnectionManager>,
+}
+
+impl SentinelSlot{
+	pub async fn open(resolver:Arc<SentinelResolver>)->RedisResult<Self>{
+		let manager = ConnectionManager::new(resolver.master().await?).await?;
+		Ok(Self{ resolver, manager:Mutex::new(manager) })
+	}
+
+	fn manager(&self)->ConnectionManager{
+		self.manager.lock().unwrap().clone()
+	}
+
+	// the failed command is not sent again , callers retry as they would after any error
+	async fn failed(&self, e:&RedisError){
+		if !(e.kind() == ErrorKind::ReadOnly || e.is_io_error() || e.is_connection_refusal() || e.is_connection_dropped()){
+			return;
+		}
+		match self.resolver.master().await{
+			Ok(client) => match ConnectionManager::new(client).await{
+				Ok(manager) => {
+					info!("Reconnected to master {:?}",self.resolver.master_name());
+					*self.manager.lock().unwrap() = manager;
+				}
+				Err(e) => warn!("Connect to master {:?} err: {:?}",self.resolver.master_name(),e),
+			},
+			Err(e) => warn!("Resolve master {:?} err: {:?}",self.resolver.master_name(),e),
+		}
+	}
+}
+
+impl std::fmt::Debug for RedisConn{
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+		match self{
+			RedisConn::Single(_) => f.write_str("RedisConn::Single"),
+			RedisConn::Sentinel(slot) => write!(f,"RedisConn::Sentinel({:?})",slot.resolver.master_name()),
+			RedisConn::Cluster(_) => f.write_str("RedisConn::Cluster"),
+		}
+	}
+}
+
+impl ConnectionLike for RedisConn{
+	fn req_packed_command<'a>(&'a mut self, cmd:&'a Cmd)->RedisFuture<'a, Value>{
+		match self{
+			RedisConn::Single(conn) => conn.req_packed_command(cmd),
+			RedisConn::Cluster(conn) => conn.req_packed_command(cmd),
+			RedisConn::Sentinel(slot) => Box::pin(async move{
+				let result = slot.manager().req_packed_command(cmd).await;
+				if let Err(ref e) = result{
+					slot.failed(e).await;
+				}
+				result
+			}),
+		}
+	}
+
+	fn req_packed_commands<'a>(&'a mut self, cmd:&'a Pipeline, offset:usize, count:usize)->RedisFuture<'a, Vec<Value>>{
+		match self{
+			RedisConn::Single(conn) => conn.req_packed_commands(cmd,offset,count),
+			RedisConn::Cluster(conn) => conn.req_packed_commands(cmd,offset,count),
+			RedisConn::Sentinel(slot) => Box::pin(async move{
+				let result = slot.manager().req_packed_commands(cmd,offset,count).await;
+				if let Err(ref e) = result{
+					slot.failed(e).await;
+				}
+				result
+			}),
+		}
+	}
+
+	fn get_db(&self)->i64{
+		match self{
+			RedisConn::Single(conn) => conn.get_db(),
+			RedisConn::Cluster(conn) => conn.get_db(),
+			RedisConn::Sentinel(slot) => slot.manager().get_db(),
+		}
+	}
+}
+
+// `host:port` of every master in a cluster , sorted so the order survives a restart
+pub async fn cluster_masters(conn:&mut RedisConn)->RedisResult<Vec<(String,u16)>>{
+	let slots:Vec<Value> = redis::cmd("CLUSTER").arg("SLOTS").query_async(conn).await?;
+	let mut masters = Vec::new();
+	for range in slots{
+		// [start , end , [host , port , id ...] , replicas ...]
+		let master = match range{
+			Value::Bulk(items) if items.len() > 2 => items[2].clone(),
+			_ => continue,
+		};
+		let (host,port) = match master{
+			Value::Bulk(node) if node.len() > 1 => (
+				redis::from_redis_value::<String>(&node[0])?,
+				redis::from_redis_value::<u16>(&node[1])?,
+			),
+			_ => continue,
+		};
+		masters.push((host,port));
+	}
+	masters.sort();
+	masters.dedup();
+	if masters.is_empty(){
+		return Err(RedisError::from((ErrorKind::ResponseError,"CLUSTER SLOTS listed no master")));
+	}
+	Ok(masters)
+}
+
+// the seed's settings pointed at another node
+pub fn node_info(seed:&ConnectionInfo, host:String, port:u16)->ConnectionInfo{
+	let addr = match seed.addr{
+		ConnectionAddr::TcpTls{ insecure, ref tls_params, .. } => ConnectionAddr::TcpTls{ host, port, insecure, tls_params:tls_params.clone() },
+		_ => ConnectionAddr::Tcp(host,port),
+	};
+	ConnectionInfo{ addr, redis:seed.redis.clone() }
+}
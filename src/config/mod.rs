@@ -1,3 +1,15 @@
+#[allow(clippy::module_inception)]
 mod config;
+mod connection;
 pub use config::Settings;
 pub use config::RedisClient;
+pub use connection::RedisConn;
+pub use config::CancelPolicy;
+pub use config::BookStorage;
+pub use config::StoreConfig;
+pub use config::StreamConfig;
+pub use config::EventConfig;
+pub use config::RetryPolicy;
+pub use config::WatcherConfig;
+pub use config::UploadConfig;
+pub use config::BackfillConfig;
@@ -1,14 +1,15 @@
 use anyhow::Result;
 use config::Settings;
-use config::RedisClient;
-use processor::FileProcessor;
+use config::{RedisClient,StoreConfig};
+use model::book::BookRedisClient;
+use model::sqlite::SqliteBookStore;
+use model::store::{BookStore,MemoryBookStore};
+use processor::{FileProcessor,WorkerPool,backfill,consume_queue,consume_stream,requeue_expired};
 use watcher::FileWatcher;
-use tokio;
-use std::path::{PathBuf,Path};
+use std::path::Path;
 use std::sync::{Arc};
-use std::cell::RefCell;
-use log::{debug,error,info,warn};
-use redis::{cmd as redisCmd, RedisResult};
+use std::time::Duration;
+use log::{error,info};
 mod config;
 mod processor;
 mod watcher;
@@ -24,73 +25,62 @@ async fn main() ->Result<()>{
     info!("Input Dir: {:?}",input_dir);
     info!("Output Dir: {:?}",output_dir);
 
-    let redis_client = Arc::new(RedisClient::new(settings.redis.url.as_str(),settings.redis.pass,settings.redis.key_prefix)?);
-    let mut conn = redis_client.get_connection().await?;
-    let processor = FileProcessor::new(
-        &(input_dir.to_string_lossy().to_string()),
-        &(output_dir.to_string_lossy().to_string()), 
-        redis_client.clone()
-    )?;    
-    let  rst:Option<Vec<String>> = redisCmd("keys").arg("book:*").query_async(&mut conn).await?;
-    info!("Keys: {:?}",rst);
-    handle_init_book_files(rst,redis_client.clone(),&processor).await?;
+    let redis_client = Arc::new(RedisClient::from_config(&settings.redis)?);
+    let books:Arc<dyn BookStore> = match settings.store {
+        StoreConfig::Redis => Arc::new(BookRedisClient::new(redis_client.clone()).await?),
+        StoreConfig::Sqlite{ ref path } => Arc::new(SqliteBookStore::open(Path::new(path))?),
+        StoreConfig::Memory => Arc::new(MemoryBookStore::new()),
+    };
+    info!("Book Store: {:?}",books);
+    let processor = Arc::new(FileProcessor::new(
+        input_dir.to_string_lossy().as_ref(),
+        output_dir.to_string_lossy().as_ref(), 
+        redis_client.clone(),
+        books.clone()
+    )?
+    .with_large_file_threshold(settings.file_processing.large_file_threshold)
+    .with_cancel_policy(settings.file_processing.cancel_policy)
+    .with_archive_dir(settings.file_processing.archive_path()?));    
+    let pool = WorkerPool::new(processor.clone(),&settings.watcher);
+    let (bprocessor,bpool,backfill_config) = (processor.clone(),pool.clone(),settings.watcher.backfill.clone());
+    tokio::spawn(async move {
+        if let Err(e) = backfill(bprocessor,bpool,backfill_config).await{
+            error!("Backfill stopped: {:?}",e);
+        }
+    });
     // info!("Redis Config: {:?}",redis_client);
+
+    model::queue::BookQueue::new(redis_client.clone()).await?.migrate_legacy_set().await?;
+    let visibility = Duration::from_secs(settings.watcher.visibility_timeout);
+    let (qprocessor,qpool) = (processor.clone(),pool.clone());
+    tokio::spawn(async move {
+        if let Err(e) = consume_queue(qprocessor,qpool,visibility).await{
+            error!("Queue consumer stopped: {:?}",e);
+        }
+    });
+    if let Some(stream) = settings.watcher.stream {
+        let (sprocessor,spool) = (processor.clone(),pool.clone());
+        tokio::spawn(async move {
+            if let Err(e) = consume_stream(sprocessor,spool,stream).await{
+                error!("Stream consumer stopped: {:?}",e);
+            }
+        });
+    }
+    let rprocessor = processor.clone();
+    tokio::spawn(async move {
+        if let Err(e) = requeue_expired(rprocessor,visibility).await{
+            error!("Queue reaper stopped: {:?}",e);
+        }
+    });
     
     // processor.store_filenames_to_redis()?;
     // processor.process_all_files().await?;
 
    // create wahcher
-    let mut watcher = FileWatcher::new(
-        &(input_dir.to_string_lossy().to_string()),
-        &(output_dir.to_string_lossy().to_string()), 
-        redis_client.clone(),
-    )?;
+    let mut watcher = FileWatcher::new(processor.clone(),pool,books)?
+        .with_check_interval(Duration::from_secs(settings.watcher.check_interval))
+        .with_upload(settings.watcher.upload.clone());
     // start watching
     watcher.start_watching().await?;
     Ok(())
 }
-
-// 在最开始时候，由于没有列表或者我们功能是后置的
-// 所以需要把所有的文件名字，依照现有处理逻辑
-// 放在redis的一个set(uuid,book_id) , set(source_name,book_id)中，任务名称放在Set队列中
-// 设置好队列，然后每隔一段时间，检查一次队列中的文件
-async fn  handle_init_book_files(book_names :Option<Vec<String>>,redis_client:Arc<RedisClient>,processor:&FileProcessor) ->Result<()>{
-    let  bcliend = RefCell::new(model::book::BookRedisClient::new(redis_client).await?);
-    if let Some(items) = book_names {
-        let mut iter = items.iter();
-        while let Some(item) = iter.next() {
-            let full:Vec<&str> = item.split("book:").collect();
-            if let Some(id) = full.last() {
-                let id = id.parse::<i32>().unwrap_or(-1);
-                if id < 0 {
-                    continue;
-                }
-                let mut bc = bcliend.borrow_mut();
-               if let Some(book) =  bc.get_book_by_id(&id).await?{
-                    match book.source_url{ 
-                        Some(source)=>{
-                            let  j = source.split("/").collect::<Vec<&str>>().last().unwrap_or(&"").to_string();
-                            info!("Get Name From Source: {:?}",&source);
-                            // let mut bc: std::cell::RefMut<'_, model::BookRedisClient> = bcliend.borrow_mut();
-                            info!("Book name: {:?}",j);
-                            // source -file absolute path
-                            let _j = std::env::current_dir()?.join(&processor.input_dir).join(&j);
-                            info!("Book source absolute file path: {:?}",&_j);
-                            let file_path = Path::new(&_j);
-                            processor.process_file(file_path, &j, book.start_count.unwrap_or(0))?;
-                        },
-                        None=>{
-                            info!("Book not found by id: {:?}",id);
-                        }
-                          
-                     } 
-               }else{
-                     error!("Book not found by id: {:?}",id);
-               }
-              
-            }
-
-        }
-    }
-    Ok(())
-}
@@ -1,11 +1,12 @@
-use std::collections::HashMap;
 use log::info;
-use serde::{de::Error, Deserialize, Serialize};
-use serde_json::error;
-use uuid::Uuid;
-use anyhow::{Context, Ok, Result};
-use redis::{cmd, Commands};
-use crate::config::RedisClient;
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Ok, Result};
+use async_trait::async_trait;
+use redis::{cmd, Script};
+use std::collections::HashMap;
+use crate::config::{BookStorage, RedisClient, RedisConn};
+use crate::model::keys::Keys;
+use crate::model::store::BookStore;
 use std::sync::{Arc};
 
 #[allow(dead_code)]
@@ -81,16 +82,48 @@ pub struct ChapterItem{
 }
 
 
+// `String` and `Option<String>` fields of `Book` , see `Book::from_fields`
+const TEXT_FIELDS:[&str;9] = ["uuid","name","avatar","author","summary","category_name","currency","display_book_name","source_url"];
+
 fn default_true()->bool{
 	true
 }
 
 pub const PREFIX_BOOK:&str = "book:";
+#[allow(dead_code)]
 pub const PREFIX_BOOK_UUID:&str ="book:uuid:";
 pub const PREFIX_BOOK_SOURCE:&str   = "book:source:";
+// legacy unacknowledged set , only read to migrate it into the pending list
 pub const PREFIX_QUEUE_BOOK_CDN:&str = "queue:book:cdn";
-// pub const PREFIX_QUEUE_BOOK_STATE:&str= "queue:book:map";
+pub const PREFIX_QUEUE_BOOK_PENDING:&str = "queue:book:pending";
+pub const PREFIX_QUEUE_BOOK_PROCESSING:&str = "queue:book:processing";
+pub const PREFIX_QUEUE_BOOK_LEASE:&str = "queue:book:lease";
+// pending , processing , lease and priority share this hash tag on a cluster , see `Keys`
+pub const QUEUE_HASH_TAG:&str = "queue:book";
+// items with a priority , highest score reserved first , ahead of the pending list
+pub const PREFIX_QUEUE_BOOK_PRIORITY:&str = "queue:book:priority";
+// books whose retries ran out , newest first
+pub const PREFIX_QUEUE_BOOK_DEAD:&str = "queue:book:dead";
+// per-book processing lock and the counter its fencing tokens come from
+pub const PREFIX_BOOK_LOCK:&str = "lock:book:";
+pub const PREFIX_BOOK_FENCE:&str = "lock:book:fence:";
+// uploaded source files no book points at , for operators
+pub const PREFIX_QUEUE_BOOK_UNMATCHED:&str = "queue:book:unmatched";
+// ids of books whose source file was removed , their output is orphaned
+pub const PREFIX_QUEUE_BOOK_ORPHANED:&str = "queue:book:orphaned";
+// what happened to a book over time , newest first , `queue:book:history:<id>`
+pub const PREFIX_QUEUE_BOOK_HISTORY:&str = "queue:book:history:";
+// source fingerprint of the last full split of a book , `queue:book:manifest:<id>`
+pub const PREFIX_QUEUE_BOOK_MANIFEST:&str = "queue:book:manifest:";
+// SCAN cursor of an unfinished startup backfill
+pub const PREFIX_QUEUE_BOOK_BACKFILL:&str = "queue:book:backfill:cursor";
+// summary of the last reconciliation pass , json
+pub const PREFIX_QUEUE_BOOK_RECONCILE:&str = "queue:book:reconcile";
+// job state hash of a book , `queue:book:map:<id>`
+pub const PREFIX_QUEUE_BOOK_STATE:&str= "queue:book:map:";
 pub const CHANNEL_PSB_BOOK_TASK:&str = "channel:psb:book:task";
+pub const CHANNEL_PSB_BOOK_RESULT:&str = "channel:psb:book:result";
+#[allow(dead_code)]
 impl Book{
 
 	pub fn to_redis_json(&self)->Result<String,anyhow::Error>{
@@ -100,70 +133,339 @@ impl Book{
 	pub fn from_redis_json(json:&str)->Result<Self,anyhow::Error>{
 		serde_json::from_str(json).context("Failed to deserialize book")
 	}
+
+	// hash fields for `BookStorage::Hash` , every value json encoded
+	pub fn to_fields(&self)->Result<Vec<(String,String)>,anyhow::Error>{
+		match serde_json::to_value(self).context("Failed to serialize book")?{
+			serde_json::Value::Object(map) => Ok(map.into_iter().map(|(k,v)| (k,v.to_string())).collect()),
+			_ => bail!("book is not an object"),
+		}
+	}
+
+	// text fields are taken as they are unless json encoded by `to_fields` , so `123` stays
+	// a name , other fields are json , a value that is not json is taken as a plain string
+	pub fn from_fields(fields:HashMap<String,String>)->Result<Self,anyhow::Error>{
+		let map = fields.into_iter()
+			.map(|(k,v)| {
+				let v = match serde_json::from_str(&v).ok(){
+					Some(serde_json::Value::String(text)) => serde_json::Value::String(text),
+					_ if TEXT_FIELDS.contains(&k.as_str()) => serde_json::Value::String(v),
+					Some(value) => value,
+					None => serde_json::Value::String(v),
+				};
+				(k,v)
+			})
+			.collect::<serde_json::Map<_,_>>();
+		serde_json::from_value(serde_json::Value::Object(map)).context("Failed to deserialize book")
+	}
+
+	// scheduling priority when a task does not bring its own , higher runs first
+	// recommended books before new ones , then by popularity
+	pub fn priority(&self)->i64{
+		(self.is_recommend as i64) * 1_000_000 + (self.is_new as i64) * 100_000 + self.pop_count.clamp(0, 99_999) as i64
+	}
+
+	// file name part of source_url , the name of the source in input_dir
+	pub fn source_name(&self)->Option<String>{
+		let source = self.source_url.as_deref()?;
+		let file = source.split('/').next_back().unwrap_or("");
+		if file.is_empty(){ None }else{ Some(file.to_string()) }
+	}
 }
 
+#[derive(Clone)]
 pub struct BookRedisClient{
-	conn: redis::aio::Connection,
+	conn: RedisConn,
+	redis_client: Arc<RedisClient>,
+	// never `Auto` , see `RedisClient::book_storage`
+	storage: BookStorage,
 }
 
+// the client and its connection are left out , they carry the credentials
+impl std::fmt::Debug for BookRedisClient{
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+		write!(f,"BookRedisClient {{ storage: {:?} }}",self.storage)
+	}
+}
+
+// rounds of `update_book_field` before it gives up on a book that keeps changing
+const UPDATE_RETRIES:usize = 10;
+
+// write ARGV[2] only while the book is still ARGV[1] , -1 when it is gone
+const CAS_STRING_SCRIPT:&str = r#"
+local old = redis.call("GET", KEYS[1])
+if not old then
+	return -1
+end
+if old ~= ARGV[1] then
+	return 0
+end
+redis.call("SET", KEYS[1], ARGV[2])
+return 1
+"#;
+
+// write field ARGV[1] as ARGV[3] only while it is still ARGV[2] , empty for a missing field
+const CAS_HASH_SCRIPT:&str = r#"
+if redis.call("EXISTS", KEYS[1]) == 0 then
+	return -1
+end
+if (redis.call("HGET", KEYS[1], ARGV[1]) or "") ~= ARGV[2] then
+	return 0
+end
+redis.call("HSET", KEYS[1], ARGV[1], ARGV[3])
+return 1
+"#;
+
+// `$.a.b` as `["a","b"]` , only plain member names are understood
+pub fn field_path(field:&str)->Result<Vec<&str>,anyhow::Error>{
+	let path:Vec<&str> = field.strip_prefix("$.").map(|p| p.split('.').collect()).unwrap_or_default();
+	if path.is_empty() || path.iter().any(|name| name.is_empty() || name.contains(['[','*'])){
+		bail!("unsupported field path {:?}",field);
+	}
+	Ok(path)
+}
+
+// like JSON.SET , every object on the way must exist , the last member is added or replaced
+pub fn set_path(doc:&mut serde_json::Value, path:&[&str], value:serde_json::Value)->Result<(),anyhow::Error>{
+	let (last,parents) = path.split_last().context("empty field path")?;
+	let mut node = doc;
+	for name in parents{
+		node = node.get_mut(*name).with_context(|| format!("no member {:?}",name))?;
+	}
+	node.as_object_mut().with_context(|| format!("{:?} is not inside an object",last))?
+		.insert(last.to_string(),value);
+	Ok(())
+}
+
+// `source_url` with its file name replaced
+pub fn renamed_source_url(source:&str, new:&str)->String{
+	match source.rfind('/'){
+		Some(pos) => format!("{}{}",&source[..=pos],new),
+		None => new.to_string(),
+	}
+}
+
+// the node a SCAN cursor belongs to sits in its top byte , a cluster is walked node by node
+const SCAN_NODE_SHIFT:u32 = 56;
+const SCAN_CURSOR_MASK:u64 = (1 << SCAN_NODE_SHIFT) - 1;
+
+#[allow(dead_code)]
 impl BookRedisClient{
 
 	pub async fn new(redis_client: Arc<RedisClient>) -> Result<Self, anyhow::Error> {
-		let conn = redis_client.get_connection().await?;
-		Ok(Self { conn })
+		let conn = redis_client.get_manager().await?;
+		let storage = redis_client.book_storage().await?;
+		Ok(Self { conn, redis_client, storage })
 	}
 
-	pub async fn set_book(&mut self, book:&Book)->Result<(),anyhow::Error>{
-		let json = book.to_redis_json()?;
-		let key = format!("{}{}",&PREFIX_BOOK , book.id.unwrap_or(0));
-		redis::cmd("JSON.SET")
-			.arg(&key)
-			.arg("$")
-			.arg(json)
-			.query_async(&mut self.conn)
-			.await?;
+	fn keys(&self)->&Keys{
+		self.redis_client.keys()
+	}
 
+	pub async fn set_book(&mut self, book:&Book)->Result<(),anyhow::Error>{
+		let key = self.keys().id(PREFIX_BOOK,book.id.unwrap_or(0));
+		match self.storage{
+			BookStorage::Hash => {
+				// fields the new book leaves out must not survive from the old one
+				redis::pipe()
+					.atomic()
+					.cmd("DEL").arg(&key)
+					.cmd("HSET").arg(&key).arg(book.to_fields()?)
+					.query_async::<_, ()>(&mut self.conn).await?;
+			}
+			BookStorage::String => {
+				cmd("SET")
+					.arg(&key)
+					.arg(book.to_redis_json()?)
+					.query_async::<_, ()>(&mut self.conn).await?;
+			}
+			_ => {
+				redis::cmd("JSON.SET")
+					.arg(&key)
+					.arg("$")
+					.arg(book.to_redis_json()?)
+					.query_async::<_, ()>(&mut self.conn)
+					.await?;
+			}
+		}
 		Ok(())
 	}
 
-	pub async fn get_book_by_id(&mut self, id:&i32)->Result<Option<Book>,anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK,id);
-		let json:Option<String> = cmd("JSON.GET")
-			.arg(&key)
-			.arg("$")
-			.query_async(&mut self.conn).await?;
-		info!("Get Book By Id: {:?}",json);
-		match json{
-			Some(j)=> {
-				let v:serde_json::Value = serde_json::from_str(&j)?;
-				if let Some(ele) = v.get(0){
-					let j = ele.to_string();
-					Ok(Some(Book::from_redis_json(&j)?))
-				}else{
-					Ok(None)
+	// the book under `key` in the storage mode , None when missing
+	async fn read_book(&mut self, key:&str)->Result<Option<Book>,anyhow::Error>{
+		match self.storage{
+			BookStorage::Hash => {
+				let fields:HashMap<String,String> = cmd("HGETALL").arg(key).query_async(&mut self.conn).await?;
+				if fields.is_empty(){
+					return Ok(None);
 				}
-			},
-			None =>Ok(None)
+				Ok(Some(Book::from_fields(fields)?))
+			}
+			BookStorage::String => {
+				let json:Option<String> = cmd("GET").arg(key).query_async(&mut self.conn).await?;
+				json.map(|j| Book::from_redis_json(&j)).transpose()
+			}
+			_ => {
+				let json:Option<String> = cmd("JSON.GET")
+					.arg(key)
+					.arg("$")
+					.query_async(&mut self.conn).await?;
+				match json{
+					Some(j) => {
+						let v:serde_json::Value = serde_json::from_str(&j)?;
+						match v.get(0){
+							Some(ele) => Ok(Some(Book::from_redis_json(&ele.to_string())?)),
+							None => Ok(None),
+						}
+					}
+					None => Ok(None),
+				}
+			}
+		}
+	}
+
+	// one SCAN step over the book keys , numeric `book:<id>` keys only
+	// returns the next cursor , 0 once the whole keyspace was walked
+	pub async fn scan_book_ids(&mut self, cursor:u64, count:usize)->Result<(u64,Vec<i32>),anyhow::Error>{
+		let mut nodes = self.redis_client.scan_nodes().await?;
+		let node = (cursor >> SCAN_NODE_SHIFT) as usize;
+		// a saved cursor of a node that is gone ends the walk
+		let conn = match nodes.get_mut(node){
+			Some(conn) => conn,
+			None => return Ok((0,Vec::new())),
+		};
+		let (next,keys):(u64,Vec<String>) = cmd("SCAN")
+			.arg(cursor & SCAN_CURSOR_MASK)
+			.arg("MATCH")
+			.arg(format!("{}[0-9]*",self.keys().key(PREFIX_BOOK)))
+			.arg("COUNT")
+			.arg(count.max(1))
+			.query_async(conn).await?;
+		let next = if next != 0{
+			((node as u64) << SCAN_NODE_SHIFT) | next
+		}else if node + 1 < nodes.len(){
+			((node + 1) as u64) << SCAN_NODE_SHIFT
+		}else{
+			0
+		};
+		Ok((next,keys.iter().filter_map(|k| self.keys().strip(k,PREFIX_BOOK)?.parse::<i32>().ok()).collect()))
+	}
+
+	// ids of every stored book , SCAN may report an id twice , duplicates are dropped
+	pub async fn book_ids(&mut self)->Result<Vec<i32>,anyhow::Error>{
+		let mut ids = Vec::new();
+		let mut cursor = 0;
+		loop{
+			let (next,found) = self.scan_book_ids(cursor,1000).await?;
+			ids.extend(found);
+			if next == 0{
+				break;
+			}
+			cursor = next;
 		}
+		ids.sort_unstable();
+		ids.dedup();
+		Ok(ids)
+	}
+
+	pub async fn get_backfill_cursor(&mut self)->Result<Option<u64>,anyhow::Error>{
+		let cursor:Option<u64> = cmd("GET").arg(self.keys().key(PREFIX_QUEUE_BOOK_BACKFILL)).query_async(&mut self.conn).await?;
+		Ok(cursor)
 	}
+
+	// None once the backfill finished
+	pub async fn set_backfill_cursor(&mut self, cursor:Option<u64>)->Result<(),anyhow::Error>{
+		let key = self.keys().key(PREFIX_QUEUE_BOOK_BACKFILL);
+		match cursor{
+			Some(cursor) => cmd("SET").arg(&key).arg(cursor).query_async::<_, ()>(&mut self.conn).await?,
+			None => cmd("DEL").arg(&key).query_async::<_, ()>(&mut self.conn).await?,
+		}
+		Ok(())
+	}
+
+	pub async fn get_book_by_id(&mut self, id:&i32)->Result<Option<Book>,anyhow::Error>{
+		let key = self.keys().id(PREFIX_BOOK,id);
+		let book = self.read_book(&key).await?;
+		info!("Get Book By Id: {:?} {:?}",id,book.as_ref().map(|b| &b.name));
+		Ok(book)
+	}
+
+	// `field` is a JSON path , `$.source_url` or `$.chapter.book_name`
+	// without RedisJSON the book is read , changed and written back only if nobody
+	// wrote it in between , otherwise the whole round is tried again
 	pub async fn update_book_field<T:Serialize>(
 		&mut self,
 		id:&i32,
 		field:&str,
 		value:T,
 	)->Result<(),anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK,id);
+		let key = self.keys().id(PREFIX_BOOK,id);
 		let json = serde_json::to_string(&value)?;
-		redis::cmd("JSON.SET")
-			.arg(&key)
-			.arg(field)
-			.arg(json)
-			.query_async(&mut self.conn).await?;
-		Ok(())
+		if !matches!(self.storage,BookStorage::Hash | BookStorage::String){
+			redis::cmd("JSON.SET")
+				.arg(&key)
+				.arg(field)
+				.arg(json)
+				.query_async::<_, ()>(&mut self.conn).await?;
+			return Ok(());
+		}
+		let path = field_path(field)?;
+		let value = serde_json::to_value(&value)?;
+		for _ in 0..UPDATE_RETRIES{
+			let written = match self.storage{
+				BookStorage::Hash => self.update_hash_field(&key,&path,value.clone()).await?,
+				_ => self.update_string_field(&key,&path,value.clone()).await?,
+			};
+			match written{
+				Some(true) => return Ok(()),
+				Some(false) => continue,
+				None => bail!("book {} not found",id),
+			}
+		}
+		bail!("book {} changed under every one of {} updates of {:?}",id,UPDATE_RETRIES,field)
+	}
+
+	// Some(false) when the book changed since it was read , None when it is missing
+	async fn update_string_field(&mut self, key:&str, path:&[&str], value:serde_json::Value)->Result<Option<bool>,anyhow::Error>{
+		let old:Option<String> = cmd("GET").arg(key).query_async(&mut self.conn).await?;
+		let old = match old{
+			Some(old) => old,
+			None => return Ok(None),
+		};
+		let mut doc:serde_json::Value = serde_json::from_str(&old)?;
+		set_path(&mut doc,path,value)?;
+		let written:i64 = Script::new(CAS_STRING_SCRIPT)
+			.key(key)
+			.arg(&old)
+			.arg(doc.to_string())
+			.invoke_async(&mut self.conn).await?;
+		Ok((written >= 0).then_some(written == 1))
+	}
+
+	// only the top level field is read and compared , other fields may change meanwhile
+	async fn update_hash_field(&mut self, key:&str, path:&[&str], value:serde_json::Value)->Result<Option<bool>,anyhow::Error>{
+		let old:Option<String> = cmd("HGET").arg(key).arg(path[0]).query_async(&mut self.conn).await?;
+		let new = if path.len() == 1{
+			value
+		}else{
+			let mut doc:serde_json::Value = match old{
+				Some(ref old) => serde_json::from_str(old)?,
+				None => bail!("no field {:?} to set {:?} in",path[0],path[1..].join(".")),
+			};
+			set_path(&mut doc,&path[1..],value)?;
+			doc
+		};
+		let written:i64 = Script::new(CAS_HASH_SCRIPT)
+			.key(key)
+			.arg(path[0])
+			.arg(old.unwrap_or_default())
+			.arg(new.to_string())
+			.invoke_async(&mut self.conn).await?;
+		Ok((written >= 0).then_some(written == 1))
 	}
 
 	pub async fn get_book_id_by_uuid(&mut self , uuid:&str)->Result<Option<i32>,anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK_UUID,uuid);
+		let key = self.keys().id(PREFIX_BOOK_UUID,uuid);
 		let json:Option<i32> = redis::cmd("GET").arg(key).query_async(&mut self.conn).await?;
 			Ok(json)
 	}
@@ -174,87 +476,188 @@ impl BookRedisClient{
 		if !name.contains(".txt") {
 			name = format!("{}.txt",name).to_string();
 		}
-		let key = format!("{}{}",&PREFIX_BOOK_SOURCE,name);
+		let key = self.keys().id(PREFIX_BOOK_SOURCE,name);
 		let json:Option<i32> = redis::cmd("GET").arg(key).query_async(&mut self.conn).await?;
 		if let Some(j) = json{
-			 let key1 = format!("{}{}",&PREFIX_BOOK,j);
-			 let json1:Option<String> =  cmd("JSON.GET")
-					.arg(&key1)
-					.arg("$")
-					.query_async(&mut self.conn).await?;
-			match json1{
-					Some(jk) => {
-						let v:serde_json::Value = serde_json::from_str(&jk)?;
-						if let Some(ele) = v.get(0){
-							let j = ele.to_string();
-							Ok(Some(Book::from_redis_json(&j)?))
-						}else{
-							Ok(None)
-						}
-					},
-					None => Ok(None),
-			 }
+			 let key1 = self.keys().id(PREFIX_BOOK,j);
+			 self.read_book(&key1).await
 		}else{
 			Ok(None)
 		}
 	}
 
 	pub async fn set_book_source(&mut self, name:&str,id:&i32)->Result<(),anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK_SOURCE,name);
+		let key = self.keys().id(PREFIX_BOOK_SOURCE,name);
 		redis::cmd("SET")
 			.arg(&key)
 			.arg(id)
-			.query_async(&mut self.conn).await?;
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// point the book at a renamed source file , the index and source_url follow
+	pub async fn rename_book_source(&mut self, id:&i32, old:&str, new:&str)->Result<(),anyhow::Error>{
+		self.set_book_source(new,id).await?;
+		redis::cmd("DEL")
+			.arg(self.keys().id(PREFIX_BOOK_SOURCE,old))
+			.query_async::<_, ()>(&mut self.conn).await?;
+		if let Some(book) = self.get_book_by_id(id).await?{
+			if let Some(source) = book.source_url{
+				self.update_book_field(id,"$.source_url",renamed_source_url(&source,new)).await?;
+			}
+		}
 		Ok(())
 	}
 
 	pub async fn set_book_uuid(&mut self, uuid:&str,id:&i32)->Result<(),anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK_UUID,uuid);
+		let key = self.keys().id(PREFIX_BOOK_UUID,uuid);
 		redis::cmd("SET")
 			.arg(&key)
 			.arg(id)
-			.query_async(&mut self.conn).await?;
+			.query_async::<_, ()>(&mut self.conn).await?;
 		Ok(())
 	}
 
 	pub async fn get_book_uuid(&mut self, uuid:&str)->Result<Option<i32>,anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK_UUID,uuid);
+		let key = self.keys().id(PREFIX_BOOK_UUID,uuid);
 		let json:Option<i32> = redis::cmd("GET").arg(key).query_async(&mut self.conn).await?;
 		Ok(json)
 	}
 
 	pub async fn get_book_by_uuid(&mut self, uuid:&str)->Result<Option<Book>,anyhow::Error>{
-		let key = format!("{}{}",&PREFIX_BOOK_UUID,uuid);
+		let key = self.keys().id(PREFIX_BOOK_UUID,uuid);
 		let json:Option<i32> = redis::cmd("GET").arg(key).query_async(&mut self.conn).await?;
 		if let Some(j) = json{
-			let key1 = format!("{}{}",&PREFIX_BOOK,j);
-			let json1:Option<String> = cmd("JSON.GET")
-			.arg(&key1)
-			.arg("$")
-			.query_async(&mut self.conn).await?;
-			let _ = match json1 {
-				Some(jk) => {
-					let v:serde_json::Value = serde_json::from_str(&jk)?;
-					if let Some(ele) = v.get(0){
-						let j = ele.to_string();
-						Ok(Some(Book::from_redis_json(&j)?))
-					}else{
-						Ok(None)
-					}
-				} ,
-				None => Ok(None),
-			};
+			let key1 = self.keys().id(PREFIX_BOOK,j);
+			return self.read_book(&key1).await;
 		}
 		Ok(None)
 	}
 
 	pub async fn push_to_queue(&mut self, name:&str)->Result<(),anyhow::Error>{
 		let name = name.split('/').collect::<Vec<&str>>().last().unwrap_or(&"").to_string();
-		redis::cmd("SADD")
-			.arg(PREFIX_QUEUE_BOOK_CDN)
+		redis::cmd("LPUSH")
+			.arg(self.keys().key(PREFIX_QUEUE_BOOK_PENDING))
 			.arg(name)
-			.query_async(&mut self.conn).await?;
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+}
+
+// each call works on a clone , the connection is shared and cheap to clone
+#[async_trait]
+impl BookStore for BookRedisClient{
+	async fn get(&self, id:i32)->Result<Option<Book>,anyhow::Error>{
+		self.clone().get_book_by_id(&id).await
+	}
+
+	async fn get_by_uuid(&self, uuid:&str)->Result<Option<Book>,anyhow::Error>{
+		self.clone().get_book_by_uuid(uuid).await
+	}
+
+	async fn get_by_source(&self, name:&str)->Result<Option<Book>,anyhow::Error>{
+		self.clone().get_book_by_source(name.to_string()).await
+	}
+
+	// the uuid and source indexes follow the book
+	async fn set(&self, book:&Book)->Result<(),anyhow::Error>{
+		let id = book.id.context("book has no id")?;
+		let mut client = self.clone();
+		client.set_book(book).await?;
+		client.set_book_uuid(&book.uuid,&id).await?;
+		if let Some(source) = book.source_name(){
+			client.set_book_source(&source,&id).await?;
+		}
 		Ok(())
 	}
+
+	async fn update(&self, id:i32, field:&str, value:serde_json::Value)->Result<(),anyhow::Error>{
+		self.clone().update_book_field(&id,field,value).await
+	}
+
+	async fn list(&self)->Result<Vec<i32>,anyhow::Error>{
+		self.clone().book_ids().await
+	}
+
+	async fn scan(&self, cursor:u64, count:usize)->Result<(u64,Vec<i32>),anyhow::Error>{
+		self.clone().scan_book_ids(cursor,count).await
+	}
+
+	async fn rename_source(&self, id:i32, old:&str, new:&str)->Result<(),anyhow::Error>{
+		self.clone().rename_book_source(&id,old,new).await
+	}
 }
 
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn field_path_splits_dotted_members(){
+		assert_eq!(field_path("$.source_url").unwrap(),vec!["source_url"]);
+		assert_eq!(field_path("$.chapter.book_name").unwrap(),vec!["chapter","book_name"]);
+	}
+
+	#[test]
+	fn field_path_rejects_what_json_set_would_read_differently(){
+		for field in ["","$","$.","source_url",".source_url","$..name","$.a..b","$.name.","$.keywords[0]","$.*","$['name']"]{
+			assert!(field_path(field).is_err(),"{:?}",field);
+		}
+	}
+
+	#[test]
+	fn set_path_adds_and_replaces_members(){
+		let mut doc = json!({"name":"a","chapter":{"book_id":1}});
+		set_path(&mut doc,&["name"],json!("b")).unwrap();
+		set_path(&mut doc,&["summary"],json!("s")).unwrap();
+		set_path(&mut doc,&["chapter","book_name"],json!("c")).unwrap();
+		assert_eq!(doc,json!({"name":"b","summary":"s","chapter":{"book_id":1,"book_name":"c"}}));
+	}
+
+	#[test]
+	fn set_path_needs_existing_parent_objects(){
+		let mut doc = json!({"name":"a","keywords":["x"]});
+		assert!(set_path(&mut doc,&["chapter","book_name"],json!("c")).is_err());
+		assert!(set_path(&mut doc,&["name","first"],json!("c")).is_err());
+		assert!(set_path(&mut doc,&["keywords","first"],json!("c")).is_err());
+		assert!(set_path(&mut doc,&[],json!("c")).is_err());
+		assert_eq!(doc,json!({"name":"a","keywords":["x"]}));
+	}
+
+	#[test]
+	fn renamed_source_url_keeps_the_directory(){
+		assert_eq!(renamed_source_url("http://files/books/a.txt","b.txt"),"http://files/books/b.txt");
+		assert_eq!(renamed_source_url("a.txt","b.txt"),"b.txt");
+	}
+
+	fn fields(pairs:&[(&str,&str)])->HashMap<String,String>{
+		let mut map:HashMap<String,String> = [("uuid","\"u\""),("name","\"n\""),("created_at","1"),("updated_at","2"),("deleted_at","0"),("category_id","3")]
+			.iter().map(|(k,v)| (k.to_string(),v.to_string())).collect();
+		map.extend(pairs.iter().map(|(k,v)| (k.to_string(),v.to_string())));
+		map
+	}
+
+	#[test]
+	fn from_fields_decodes_json_values(){
+		let book = Book::from_fields(fields(&[("id","7"),("keywords","[\"a\",\"b\"]"),("is_new","false"),("start_count","12")])).unwrap();
+		assert_eq!((book.id,book.uuid.as_str(),book.created_at,book.category_id),(Some(7),"u",1,3));
+		assert_eq!(book.keywords,Some(vec!["a".to_string(),"b".to_string()]));
+		assert_eq!((book.is_new,book.start_count),(false,Some(12)));
+	}
+
+	#[test]
+	fn from_fields_keeps_raw_text_fields_as_text(){
+		let book = Book::from_fields(fields(&[("name","123"),("author","true"),("summary","[1]"),("source_url","http://files/a.txt")])).unwrap();
+		assert_eq!(book.name,"123");
+		assert_eq!(book.author.as_deref(),Some("true"));
+		assert_eq!(book.summary.as_deref(),Some("[1]"));
+		assert_eq!(book.source_name().as_deref(),Some("a.txt"));
+	}
+
+	#[test]
+	fn from_fields_rejects_bad_numbers(){
+		assert!(Book::from_fields(fields(&[("category_id","\"three\"")])).is_err());
+		assert!(Book::from_fields(fields(&[("created_at","soon")])).is_err());
+	}
+}
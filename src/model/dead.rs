@@ -0,0 +1,84 @@
+use anyhow::Result;
+use log::warn;
+use redis::cmd;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::config::{RedisClient, RedisConn};
+use crate::model::book::PREFIX_QUEUE_BOOK_DEAD;
+use crate::processor::splitter::SplitOptions;
+
+// one failed attempt of a book job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attempt{
+	pub attempt:u32,
+	pub at:i64,
+	pub error:String,
+}
+
+// a book job that failed every attempt , enough to run it again as is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter{
+	pub book_id:i32,
+	pub uuid:String,
+	pub path:String,
+	pub name:String,
+	#[serde(flatten)]
+	pub split:SplitOptions,
+	#[serde(default)]
+	pub priority:i64,
+	pub attempts:Vec<Attempt>,
+	pub failed_at:i64,
+}
+
+pub struct DeadLetterClient{
+	conn: RedisConn,
+	key: String,
+}
+
+impl DeadLetterClient{
+
+	pub async fn new(redis_client: Arc<RedisClient>) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		Ok(Self { conn, key: redis_client.keys().key(PREFIX_QUEUE_BOOK_DEAD) })
+	}
+
+	pub async fn push(&mut self, letter:&DeadLetter)->Result<(),anyhow::Error>{
+		cmd("LPUSH")
+			.arg(&self.key)
+			.arg(serde_json::to_string(letter)?)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// remove and return the dead letters of one book , or all of them when `book_id` is None
+	pub async fn take(&mut self, book_id:Option<i32>)->Result<Vec<DeadLetter>,anyhow::Error>{
+		let raw:Vec<String> = cmd("LRANGE")
+			.arg(&self.key)
+			.arg(0)
+			.arg(-1)
+			.query_async(&mut self.conn).await?;
+		let mut letters = Vec::new();
+		for item in raw{
+			let letter:DeadLetter = match serde_json::from_str(&item){
+				Ok(letter) => letter,
+				Err(e) => {
+					warn!("Skip unreadable dead letter {:?}: {:?}",item,e);
+					continue;
+				}
+			};
+			if book_id.is_some_and(|id| id != letter.book_id){
+				continue;
+			}
+			// only the caller that removed it gets it , two requeues do not duplicate it
+			let removed:i64 = cmd("LREM")
+				.arg(&self.key)
+				.arg(1)
+				.arg(&item)
+				.query_async(&mut self.conn).await?;
+			if removed > 0{
+				letters.push(letter);
+			}
+		}
+		Ok(letters)
+	}
+}
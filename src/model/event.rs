@@ -0,0 +1,63 @@
+use anyhow::Result;
+use redis::cmd;
+use serde::Serialize;
+use std::sync::Arc;
+use crate::config::{EventConfig, RedisClient, RedisConn};
+use crate::model::state::JobStatus;
+
+// field of a result stream entry holding the event json
+pub const STREAM_FIELD_EVENT:&str = "event";
+
+// published once a book job ends , whatever the outcome
+#[derive(Debug, Clone, Serialize)]
+pub struct JobEvent{
+	pub book_id:i32,
+	pub uuid:String,
+	pub status:JobStatus,
+	pub chapters:u64,
+	// indexes of chapter files this job created
+	pub changed:Vec<usize>,
+	pub duration_ms:u64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error:Option<String>,
+}
+
+pub struct EventPublisher{
+	conn: RedisConn,
+	config: EventConfig,
+	// channel and stream of `config` under the key prefix
+	channel: Option<String>,
+	stream: Option<String>,
+}
+
+impl EventPublisher{
+
+	pub async fn new(redis_client: Arc<RedisClient>, config: EventConfig) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		let channel = config.channel.as_deref().map(|name| redis_client.keys().key(name));
+		let stream = config.stream.as_deref().map(|name| redis_client.keys().key(name));
+		Ok(Self { conn, config, channel, stream })
+	}
+
+	pub async fn publish(&mut self, event:&JobEvent)->Result<(),anyhow::Error>{
+		let json = serde_json::to_string(event)?;
+		if let Some(ref channel) = self.channel{
+			cmd("PUBLISH")
+				.arg(channel)
+				.arg(&json)
+				.query_async::<_, ()>(&mut self.conn).await?;
+		}
+		if let Some(ref stream) = self.stream{
+			let mut xadd = cmd("XADD");
+			xadd.arg(stream);
+			if let Some(maxlen) = self.config.stream_maxlen{
+				xadd.arg("MAXLEN").arg("~").arg(maxlen);
+			}
+			xadd.arg("*")
+				.arg(STREAM_FIELD_EVENT)
+				.arg(&json)
+				.query_async::<_, ()>(&mut self.conn).await?;
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,108 @@
+use std::fmt::Display;
+
+use crate::model::book::{PREFIX_QUEUE_BOOK_LEASE, PREFIX_QUEUE_BOOK_PENDING, PREFIX_QUEUE_BOOK_PRIORITY, PREFIX_QUEUE_BOOK_PROCESSING, QUEUE_HASH_TAG};
+
+// the keys that share the queue hash tag
+const QUEUE_KEYS:[&str;4] = [PREFIX_QUEUE_BOOK_PENDING, PREFIX_QUEUE_BOOK_PROCESSING, PREFIX_QUEUE_BOOK_LEASE, PREFIX_QUEUE_BOOK_PRIORITY];
+
+// builds every key and channel name , `<key_prefix>:<name>` , names are the `PREFIX_*`
+// and `CHANNEL_*` constants , an empty prefix leaves them as they are
+// queue keys get a hash tag on a cluster , `<key_prefix>:{queue:book}:pending` ,
+// so the script and BLMOVE that touch several of them stay in one slot
+#[derive(Debug, Clone, Default)]
+pub struct Keys{
+	prefix:String,
+	cluster:bool,
+}
+
+impl Keys{
+	pub fn new(key_prefix:&str, cluster:bool)->Self{
+		let prefix = match key_prefix.trim_end_matches(':'){
+			"" => String::new(),
+			prefix => format!("{}:",prefix),
+		};
+		Self{ prefix, cluster }
+	}
+
+	pub fn key(&self, name:&str)->String{
+		match name.strip_prefix(QUEUE_HASH_TAG){
+			Some(rest) if self.cluster && QUEUE_KEYS.contains(&name) => format!("{}{{{}}}{}",self.prefix,QUEUE_HASH_TAG,rest),
+			_ => format!("{}{}",self.prefix,name),
+		}
+	}
+
+	// `book:` and 42 give `book:42`
+	pub fn id(&self, name:&str, id:impl Display)->String{
+		self.key(&format!("{}{}",name,id))
+	}
+
+	// like `id` , with the id as hash tag on a cluster , `lock:book:{42}` , so keys
+	// of one book used by the same script stay in one slot
+	pub fn tagged(&self, name:&str, id:impl Display)->String{
+		match self.cluster{
+			true => format!("{}{}{{{}}}",self.prefix,name,id),
+			false => self.id(name,id),
+		}
+	}
+
+	// what follows `name` in a key built by `id` , for keys found by SCAN
+	pub fn strip<'a>(&self, key:&'a str, name:&str)->Option<&'a str>{
+		key.strip_prefix(self.prefix.as_str())?.strip_prefix(name)
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+	use crate::model::book::{PREFIX_BOOK, PREFIX_BOOK_FENCE, PREFIX_BOOK_LOCK, PREFIX_QUEUE_BOOK_DEAD};
+
+	#[test]
+	fn prefix_is_joined_with_one_colon(){
+		assert_eq!(Keys::new("xr",false).key(PREFIX_QUEUE_BOOK_DEAD),"xr:queue:book:dead");
+		assert_eq!(Keys::new("xr:",false).key(PREFIX_QUEUE_BOOK_DEAD),"xr:queue:book:dead");
+		assert_eq!(Keys::new("xr::",false).id(PREFIX_BOOK,42),"xr:book:42");
+	}
+
+	#[test]
+	fn empty_prefix_leaves_names_as_they_are(){
+		for keys in [Keys::new("",false),Keys::new(":",false),Keys::default()]{
+			assert_eq!(keys.key(PREFIX_QUEUE_BOOK_PENDING),"queue:book:pending");
+			assert_eq!(keys.id(PREFIX_BOOK,42),"book:42");
+		}
+	}
+
+	#[test]
+	fn queue_keys_share_a_hash_tag_on_a_cluster(){
+		let keys = Keys::new("xr",true);
+		assert_eq!(keys.key(PREFIX_QUEUE_BOOK_PENDING),"xr:{queue:book}:pending");
+		assert_eq!(keys.key(PREFIX_QUEUE_BOOK_PROCESSING),"xr:{queue:book}:processing");
+		assert_eq!(keys.key(PREFIX_QUEUE_BOOK_LEASE),"xr:{queue:book}:lease");
+		assert_eq!(keys.key(PREFIX_QUEUE_BOOK_PRIORITY),"xr:{queue:book}:priority");
+		assert_eq!(Keys::new("",true).key(PREFIX_QUEUE_BOOK_PENDING),"{queue:book}:pending");
+	}
+
+	#[test]
+	fn other_keys_get_no_hash_tag(){
+		let keys = Keys::new("xr",true);
+		// the dead letter list is not touched together with the queue keys
+		assert_eq!(keys.key(PREFIX_QUEUE_BOOK_DEAD),"xr:queue:book:dead");
+		assert_eq!(keys.id(PREFIX_BOOK,42),"xr:book:42");
+		assert_eq!(Keys::new("xr",false).key(PREFIX_QUEUE_BOOK_PENDING),"xr:queue:book:pending");
+	}
+
+	#[test]
+	fn tagged_keys_use_the_id_as_hash_tag_on_a_cluster(){
+		// the lock and its fence counter land in the same slot
+		assert_eq!(Keys::new("xr",true).tagged(PREFIX_BOOK_LOCK,42),"xr:lock:book:{42}");
+		assert_eq!(Keys::new("xr",true).tagged(PREFIX_BOOK_FENCE,42),"xr:lock:book:fence:{42}");
+		assert_eq!(Keys::new("xr",false).tagged(PREFIX_BOOK_LOCK,42),"xr:lock:book:42");
+	}
+
+	#[test]
+	fn strip_returns_what_follows_the_name(){
+		let keys = Keys::new("xr",false);
+		assert_eq!(keys.strip(&keys.id(PREFIX_BOOK,42),PREFIX_BOOK),Some("42"));
+		assert_eq!(keys.strip("other:book:42",PREFIX_BOOK),None);
+		assert_eq!(keys.strip("xr:queue:book:dead",PREFIX_BOOK),None);
+	}
+}
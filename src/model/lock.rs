@@ -0,0 +1,141 @@
+use anyhow::Result;
+use redis::{cmd, Script};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::{RedisClient, RedisConn};
+use crate::model::book::{PREFIX_BOOK_FENCE, PREFIX_BOOK_LOCK};
+use crate::model::keys::Keys;
+
+// the fence only grows when the lock is taken , a contended attempt uses up no token
+const ACQUIRE_SCRIPT:&str = r#"
+if redis.call("EXISTS", KEYS[1]) == 1 then
+	return 0
+end
+local token = redis.call("INCR", KEYS[2])
+redis.call("SET", KEYS[1], token .. ":" .. ARGV[1], "PX", ARGV[2])
+return token
+"#;
+// renew or release only while the lock still holds our value
+const RENEW_SCRIPT:&str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+	return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+const RELEASE_SCRIPT:&str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+	return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+// a held book lock
+// `token` grows with every acquisition of the book , a writer whose token is no
+// longer the current fence lost the book to a newer holder , see `BookLock::owns`
+#[derive(Debug, Clone)]
+pub struct LockGuard{
+	pub book_id:i32,
+	pub token:u64,
+	value:String,
+}
+
+// returned when another owner holds the book , or took it over from this one
+// `expires_in` is how long the lock lives unless its holder renews it
+#[derive(Debug)]
+pub struct Locked{
+	pub book_id:i32,
+	pub holder:Option<String>,
+	pub expires_in:Option<Duration>,
+}
+
+impl std::fmt::Display for Locked{
+	fn fmt(&self, f:&mut std::fmt::Formatter<'_>)->std::fmt::Result{
+		match self.holder{
+			Some(ref holder) => write!(f,"book {} locked by {}",self.book_id,holder),
+			None => write!(f,"book {} locked by another owner",self.book_id),
+		}
+	}
+}
+
+impl std::error::Error for Locked{}
+
+// lease lock per book id , `lock:book:<id>` holds `<token>:<owner>` and expires
+// unless renewed , so a crashed holder frees the book after the ttl
+// the lock and its fence share a hash tag on a cluster , they are taken in one script
+#[derive(Clone)]
+pub struct BookLock{
+	conn: RedisConn,
+	keys: Keys,
+	ttl:Duration,
+}
+
+impl BookLock{
+
+	pub async fn new(redis_client: Arc<RedisClient>, ttl:Duration) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		Ok(Self { conn, keys: redis_client.keys().clone(), ttl })
+	}
+
+	fn key(&self, book_id:i32)->String{
+		self.keys.tagged(PREFIX_BOOK_LOCK,book_id)
+	}
+
+	fn fence_key(&self, book_id:i32)->String{
+		self.keys.tagged(PREFIX_BOOK_FENCE,book_id)
+	}
+
+	// None when another owner holds the book
+	pub async fn acquire(&mut self, book_id:i32, owner:&str)->Result<Option<LockGuard>,anyhow::Error>{
+		let token:u64 = Script::new(ACQUIRE_SCRIPT)
+			.key(self.key(book_id))
+			.key(self.fence_key(book_id))
+			.arg(owner)
+			.arg(self.ttl.as_millis() as u64)
+			.invoke_async(&mut self.conn).await?;
+		if token == 0{
+			return Ok(None);
+		}
+		Ok(Some(LockGuard{ book_id, token, value:format!("{}:{}",token,owner) }))
+	}
+
+	// false once a newer holder took the book , checked before output is written
+	pub async fn owns(&mut self, guard:&LockGuard)->Result<bool,anyhow::Error>{
+		let fence:Option<u64> = cmd("GET").arg(self.fence_key(guard.book_id)).query_async(&mut self.conn).await?;
+		Ok(fence == Some(guard.token))
+	}
+
+	// `<token>:<owner>` of the current holder
+	pub async fn holder(&mut self, book_id:i32)->Result<Option<String>,anyhow::Error>{
+		let value:Option<String> = cmd("GET").arg(self.key(book_id)).query_async(&mut self.conn).await?;
+		Ok(value)
+	}
+
+	// time left before the lock of a book expires , None when nobody holds it
+	pub async fn expires_in(&mut self, book_id:i32)->Result<Option<Duration>,anyhow::Error>{
+		let ttl:i64 = cmd("PTTL").arg(self.key(book_id)).query_async(&mut self.conn).await?;
+		Ok((ttl >= 0).then(|| Duration::from_millis(ttl as u64)))
+	}
+
+	// push the expiry forward , false when the lock was lost
+	pub async fn renew(&mut self, guard:&LockGuard)->Result<bool,anyhow::Error>{
+		let renewed:i64 = Script::new(RENEW_SCRIPT)
+			.key(self.key(guard.book_id))
+			.arg(&guard.value)
+			.arg(self.ttl.as_millis() as u64)
+			.invoke_async(&mut self.conn).await?;
+		Ok(renewed == 1)
+	}
+
+	// false when the lock had already expired or passed to another owner
+	pub async fn release(&mut self, guard:&LockGuard)->Result<bool,anyhow::Error>{
+		let released:i64 = Script::new(RELEASE_SCRIPT)
+			.key(self.key(guard.book_id))
+			.arg(&guard.value)
+			.invoke_async(&mut self.conn).await?;
+		Ok(released == 1)
+	}
+
+	pub fn ttl(&self)->Duration{
+		self.ttl
+	}
+}
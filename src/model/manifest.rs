@@ -0,0 +1,78 @@
+use anyhow::Result;
+use redis::cmd;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use crate::config::{RedisClient, RedisConn};
+use crate::model::book::PREFIX_QUEUE_BOOK_MANIFEST;
+use crate::model::keys::Keys;
+
+// what a finished full split of a book was made from , `queue:book:manifest:<id>`
+// a later run with the same source and stop has nothing to do unless chapters went missing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest{
+	pub size:u64,
+	// source mtime in unix milliseconds
+	pub mtime:u64,
+	// sha256 of the source , hex
+	pub hash:String,
+	pub stop:i32,
+	pub chapters:u64,
+	pub at:i64,
+}
+
+// size and mtime of a source file , cheap to read
+pub fn source_stat(path:&Path)->Result<(u64,u64),anyhow::Error>{
+	let meta = fs::metadata(path)?;
+	let mtime = meta.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+	Ok((meta.len(),mtime))
+}
+
+// reads the whole file , run it off the async runtime
+pub fn source_hash(path:&Path)->Result<String,anyhow::Error>{
+	let mut file = File::open(path)?;
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0u8; 1024 * 1024];
+	loop{
+		let n = file.read(&mut buf)?;
+		if n == 0{
+			break;
+		}
+		hasher.update(&buf[..n]);
+	}
+	Ok(hasher.finalize().iter().map(|b| format!("{:02x}",b)).collect())
+}
+
+pub struct ManifestClient{
+	conn: RedisConn,
+	keys: Keys,
+}
+
+impl ManifestClient{
+
+	pub async fn new(redis_client: Arc<RedisClient>) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		Ok(Self { conn, keys: redis_client.keys().clone() })
+	}
+
+	fn key(&self, id:i32)->String{
+		self.keys.id(PREFIX_QUEUE_BOOK_MANIFEST,id)
+	}
+
+	pub async fn get(&mut self, id:i32)->Result<Option<Manifest>,anyhow::Error>{
+		let json:Option<String> = cmd("GET").arg(self.key(id)).query_async(&mut self.conn).await?;
+		Ok(json.and_then(|json| serde_json::from_str(&json).ok()))
+	}
+
+	pub async fn set(&mut self, id:i32, manifest:&Manifest)->Result<(),anyhow::Error>{
+		cmd("SET")
+			.arg(self.key(id))
+			.arg(serde_json::to_string(manifest)?)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+}
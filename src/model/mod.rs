@@ -1,9 +1,13 @@
 pub mod book;
+pub mod dead;
+pub mod event;
+pub mod keys;
+pub mod lock;
+pub mod manifest;
+pub mod queue;
+pub mod sqlite;
+pub mod state;
+pub mod store;
+pub mod stream;
+pub mod task;
 
-pub use book::Book;
-pub use book::BookRedisClient;
-pub use book::PREFIX_BOOK;
-pub use book::PREFIX_BOOK_SOURCE;
-pub use book::PREFIX_QUEUE_BOOK_CDN;
-pub use book::PREFIX_BOOK_UUID;
-pub use book::CHANNEL_PSB_BOOK_TASK;
\ No newline at end of file
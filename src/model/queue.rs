@@ -0,0 +1,209 @@
+use anyhow::Result;
+use log::{info, warn};
+use redis::{cmd, Script};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::{RedisClient, RedisConn};
+use crate::model::book::{PREFIX_QUEUE_BOOK_CDN, PREFIX_QUEUE_BOOK_LEASE, PREFIX_QUEUE_BOOK_PENDING, PREFIX_QUEUE_BOOK_PRIORITY, PREFIX_QUEUE_BOOK_PROCESSING};
+
+// move the highest scored item of the priority set into the processing list , with its lease
+const RESERVE_PRIORITY_SCRIPT:&str = r#"
+local item = redis.call("ZPOPMAX", KEYS[1])
+if #item == 0 then
+	return nil
+end
+redis.call("LPUSH", KEYS[2], item[1])
+redis.call("HSET", KEYS[3], item[1], ARGV[1])
+return item
+"#;
+
+// an item handed out by `reserve` , with its score when it came from the priority set
+#[derive(Debug)]
+pub struct Reserved{
+	pub item:String,
+	pub priority:Option<i64>,
+}
+
+// reliable task queue
+// items wait in the priority set or the pending list , the set is always drained first , `reserve` moves one into the processing list
+// and gives it a lease , `ack` drops it for good , an item whose lease ran out
+// ( worker died or hung ) goes back to pending on `requeue_expired`
+// BLMOVE cannot run in a script , its lease is written right after the move
+// every other command goes over the shared connections
+pub struct BookQueue{
+	conn: RedisConn,
+	// BLMOVE blocks its connection , so it gets one of its own , opened by the first `reserve`
+	blocking: Option<RedisConn>,
+	redis_client: Arc<RedisClient>,
+	// hash tagged on a cluster , the script and BLMOVE touch two of them at once
+	pending:String,
+	processing:String,
+	lease:String,
+	priority:String,
+	legacy:String,
+	// processing items `requeue_expired` found without a lease , requeued when still so on the next pass
+	unleased:HashSet<String>,
+}
+
+fn now_millis()->u64{
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[allow(dead_code)]
+impl BookQueue{
+
+	pub async fn new(redis_client: Arc<RedisClient>) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		let key = |key:&str| redis_client.keys().key(key);
+		Ok(Self {
+			conn,
+			blocking: None,
+			pending: key(PREFIX_QUEUE_BOOK_PENDING),
+			processing: key(PREFIX_QUEUE_BOOK_PROCESSING),
+			lease: key(PREFIX_QUEUE_BOOK_LEASE),
+			priority: key(PREFIX_QUEUE_BOOK_PRIORITY),
+			legacy: key(PREFIX_QUEUE_BOOK_CDN),
+			unleased: HashSet::new(),
+			redis_client,
+		})
+	}
+
+	pub async fn push(&mut self, item:&str)->Result<(),anyhow::Error>{
+		cmd("LPUSH")
+			.arg(&self.pending)
+			.arg(item)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// queue an item ahead of every pending one with a lower score
+	pub async fn push_priority(&mut self, item:&str, priority:i64)->Result<(),anyhow::Error>{
+		cmd("ZADD")
+			.arg(&self.priority)
+			.arg(priority)
+			.arg(item)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// wait up to `wait` for an item , it stays invisible to others for `visibility`
+	// only the pending list is waited on , a priority item pushed meanwhile is taken on the next call
+	// a requeued item goes back to the pending list without its score
+	pub async fn reserve(&mut self, visibility:Duration, wait:Duration)->Result<Option<Reserved>,anyhow::Error>{
+		let top:Option<(String,f64)> = Script::new(RESERVE_PRIORITY_SCRIPT)
+			.key(&self.priority)
+			.key(&self.processing)
+			.key(&self.lease)
+			.arg(now_millis() + visibility.as_millis() as u64)
+			.invoke_async(&mut self.conn).await?;
+		if let Some((item,score)) = top{
+			return Ok(Some(Reserved{ item, priority:Some(score as i64) }));
+		}
+		let mut blmove = cmd("BLMOVE");
+		blmove.arg(&self.pending).arg(&self.processing).arg("RIGHT").arg("LEFT").arg(wait.as_secs_f64());
+		let blocking = match self.blocking.as_mut(){
+			Some(conn) => conn,
+			None => self.blocking.insert(self.redis_client.get_connection().await?),
+		};
+		let item:Option<String> = blmove.query_async(blocking).await?;
+		if let Some(ref item) = item{
+			self.extend(item, visibility).await?;
+		}
+		Ok(item.map(|item| Reserved{ item, priority:None }))
+	}
+
+	// push the lease of a reserved item forward , used as a heartbeat by long jobs
+	pub async fn extend(&mut self, item:&str, visibility:Duration)->Result<(),anyhow::Error>{
+		cmd("HSET")
+			.arg(&self.lease)
+			.arg(item)
+			.arg(now_millis() + visibility.as_millis() as u64)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// the item is done , false when it was no longer reserved ( lease expired and requeued )
+	pub async fn ack(&mut self, item:&str)->Result<bool,anyhow::Error>{
+		let removed:i64 = cmd("LREM")
+			.arg(&self.processing)
+			.arg(1)
+			.arg(item)
+			.query_async(&mut self.conn).await?;
+		cmd("HDEL")
+			.arg(&self.lease)
+			.arg(item)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(removed > 0)
+	}
+
+	// give a reserved item back right away
+	pub async fn nack(&mut self, item:&str)->Result<(),anyhow::Error>{
+		if self.ack(item).await?{
+			self.requeue(item).await?;
+		}
+		Ok(())
+	}
+
+	// move every reserved item whose lease ran out back to pending
+	// an item without a lease counts as expired once the previous pass found it without one too ,
+	// a worker that died right after BLMOVE never writes it , one that is about to is given the time
+	pub async fn requeue_expired(&mut self)->Result<usize,anyhow::Error>{
+		let items:Vec<String> = cmd("LRANGE")
+			.arg(&self.processing)
+			.arg(0)
+			.arg(-1)
+			.query_async(&mut self.conn).await?;
+		if items.is_empty(){
+			self.unleased.clear();
+			return Ok(0);
+		}
+		let leases:HashMap<String,u64> = cmd("HGETALL")
+			.arg(&self.lease)
+			.query_async(&mut self.conn).await?;
+		let now = now_millis();
+		let seen = std::mem::take(&mut self.unleased);
+		let mut count = 0;
+		for item in items{
+			match leases.get(&item){
+				Some(deadline) if *deadline > now => continue,
+				None if !seen.contains(&item) => {
+					self.unleased.insert(item);
+					continue;
+				}
+				_ => {}
+			}
+			// only the caller that removed it pushes it back , so two reapers do not duplicate it
+			if self.ack(&item).await?{
+				warn!("Lease expired , requeue: {:?}",item);
+				self.requeue(&item).await?;
+				count += 1;
+			}
+		}
+		Ok(count)
+	}
+
+	// move members of the old unacknowledged set into the pending list
+	pub async fn migrate_legacy_set(&mut self)->Result<usize,anyhow::Error>{
+		let kind:String = cmd("TYPE").arg(&self.legacy).query_async(&mut self.conn).await?;
+		if kind != "set"{
+			return Ok(0);
+		}
+		let items:Vec<String> = cmd("SMEMBERS").arg(&self.legacy).query_async(&mut self.conn).await?;
+		for item in items.iter(){
+			self.push(item).await?;
+		}
+		cmd("DEL").arg(&self.legacy).query_async::<_, ()>(&mut self.conn).await?;
+		info!("Migrated {} items from {:?}",items.len(),self.legacy);
+		Ok(items.len())
+	}
+
+	// requeued items go to the consuming end so they are picked up next
+	async fn requeue(&mut self, item:&str)->Result<(),anyhow::Error>{
+		cmd("RPUSH")
+			.arg(&self.pending)
+			.arg(item)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+}
@@ -0,0 +1,172 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::model::book::{field_path, renamed_source_url, set_path, Book};
+use crate::model::store::{source_file, BookStore};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS books (
+	id INTEGER PRIMARY KEY,
+	uuid TEXT NOT NULL,
+	source TEXT,
+	json TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS books_uuid ON books (uuid);
+CREATE INDEX IF NOT EXISTS books_source ON books (source);
+";
+
+// books in a sqlite file , for deployments without RedisJSON or a shared redis
+// `uuid` and `source` are kept next to the json so lookups by them use an index
+#[derive(Debug, Clone)]
+pub struct SqliteBookStore {
+	conn: Arc<Mutex<Connection>>,
+}
+
+fn read(conn: &Connection, sql: &str, key: &dyn rusqlite::ToSql) -> Result<Option<Book>> {
+	let json: Option<String> = conn.query_row(sql, [key], |row| row.get(0)).optional()?;
+	json.map(|json| Book::from_redis_json(&json)).transpose()
+}
+
+// the row of a book , with the columns it is looked up by
+struct Row {
+	id: i32,
+	uuid: String,
+	source: Option<String>,
+	json: String,
+}
+
+impl Row {
+	fn new(book: &Book) -> Result<Self> {
+		Ok(Self {
+			id: book.id.context("book has no id")?,
+			uuid: book.uuid.clone(),
+			source: book.source_name(),
+			json: book.to_redis_json()?,
+		})
+	}
+
+	fn write(&self, conn: &Connection) -> Result<()> {
+		conn.execute(
+			"INSERT OR REPLACE INTO books (id, uuid, source, json) VALUES (?1, ?2, ?3, ?4)",
+			params![self.id, self.uuid, self.source, self.json],
+		)?;
+		Ok(())
+	}
+}
+
+impl SqliteBookStore {
+	// the file and its table are created when missing
+	pub fn open(path: &Path) -> Result<Self> {
+		let conn = Connection::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+		conn.execute_batch(SCHEMA)?;
+		Ok(Self { conn: Arc::new(Mutex::new(conn)) })
+	}
+
+	// sqlite blocks , calls run off the async runtime
+	async fn call<T, F>(&self, f: F) -> Result<T>
+	where
+		T: Send + 'static,
+		F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+	{
+		let conn = self.conn.clone();
+		tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await?
+	}
+}
+
+#[async_trait]
+impl BookStore for SqliteBookStore {
+	async fn get(&self, id: i32) -> Result<Option<Book>> {
+		self.call(move |conn| read(conn, "SELECT json FROM books WHERE id = ?1", &id)).await
+	}
+
+	async fn get_by_uuid(&self, uuid: &str) -> Result<Option<Book>> {
+		let uuid = uuid.to_string();
+		self.call(move |conn| read(conn, "SELECT json FROM books WHERE uuid = ?1", &uuid)).await
+	}
+
+	async fn get_by_source(&self, name: &str) -> Result<Option<Book>> {
+		let name = source_file(name);
+		self.call(move |conn| read(conn, "SELECT json FROM books WHERE source = ?1", &name)).await
+	}
+
+	async fn set(&self, book: &Book) -> Result<()> {
+		let row = Row::new(book)?;
+		self.call(move |conn| row.write(conn)).await
+	}
+
+	// read and written in one transaction , the connection is not shared meanwhile
+	async fn update(&self, id: i32, field: &str, value: serde_json::Value) -> Result<()> {
+		let path: Vec<String> = field_path(field)?.into_iter().map(String::from).collect();
+		self.call(move |conn| {
+			let tx = conn.transaction()?;
+			let json: String = tx.query_row("SELECT json FROM books WHERE id = ?1", [id], |row| row.get(0))
+				.optional()?
+				.with_context(|| format!("book {} not found", id))?;
+			let mut doc: serde_json::Value = serde_json::from_str(&json)?;
+			let path: Vec<&str> = path.iter().map(String::as_str).collect();
+			set_path(&mut doc, &path, value)?;
+			Row::new(&serde_json::from_value(doc)?)?.write(&tx)?;
+			tx.commit()?;
+			Ok(())
+		}).await
+	}
+
+	async fn list(&self) -> Result<Vec<i32>> {
+		self.call(|conn| {
+			let mut stmt = conn.prepare("SELECT id FROM books ORDER BY id")?;
+			let ids = stmt.query_map([], |row| row.get(0))?.collect::<Result<Vec<i32>, _>>()?;
+			Ok(ids)
+		}).await
+	}
+
+	async fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<i32>)> {
+		let count = count.max(1);
+		let ids = self.call(move |conn| {
+			let mut stmt = conn.prepare("SELECT id FROM books WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+			let ids = stmt.query_map(params![cursor as i64, count as i64], |row| row.get(0))?.collect::<Result<Vec<i32>, _>>()?;
+			Ok(ids)
+		}).await?;
+		let next = if ids.len() < count { 0 } else { ids.last().map(|id| *id as u64).unwrap_or(0) };
+		Ok((next, ids))
+	}
+
+	async fn rename_source(&self, id: i32, _old: &str, new: &str) -> Result<()> {
+		if let Some(source) = self.get(id).await?.and_then(|book| book.source_url) {
+			self.update(id, "$.source_url", renamed_source_url(&source, new).into()).await?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::model::store::tests::{book, check_scan, check_store};
+
+	fn store() -> SqliteBookStore {
+		SqliteBookStore::open(Path::new(":memory:")).unwrap()
+	}
+
+	#[tokio::test]
+	async fn sqlite_store() {
+		check_store(&store()).await;
+	}
+
+	#[tokio::test]
+	async fn sqlite_scan() {
+		check_scan(&store()).await;
+	}
+
+	#[tokio::test]
+	async fn books_survive_reopening() {
+		let path = std::env::temp_dir().join(format!("xreader-books-{}.db", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		SqliteBookStore::open(&path).unwrap().set(&book(4, "d.txt")).await.unwrap();
+		let got = SqliteBookStore::open(&path).unwrap().get(4).await.unwrap();
+		std::fs::remove_file(&path).unwrap();
+		assert_eq!(got.and_then(|book| book.source_name()).as_deref(), Some("d.txt"));
+	}
+}
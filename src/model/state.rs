@@ -0,0 +1,269 @@
+use anyhow::Result;
+use log::warn;
+use redis::{cmd, Script};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::{RedisClient, RedisConn};
+use crate::model::book::{PREFIX_QUEUE_BOOK_HISTORY, PREFIX_QUEUE_BOOK_ORPHANED, PREFIX_QUEUE_BOOK_STATE, PREFIX_QUEUE_BOOK_UNMATCHED};
+use crate::model::keys::Keys;
+
+// entries kept in the history list of a book
+const HISTORY_LEN:i64 = 100;
+// set the status unless it is ARGV[1]
+const QUEUED_SCRIPT:&str = r#"
+if redis.call("HGET", KEYS[1], "status") == ARGV[1] then
+	return 0
+end
+redis.call("HSET", KEYS[1], "status", ARGV[2])
+return 1
+"#;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus{
+	Queued,
+	Running,
+	Done,
+	Failed,
+	Cancelled,
+}
+
+impl JobStatus{
+	pub fn as_str(&self)->&'static str{
+		match self{
+			JobStatus::Queued => "queued",
+			JobStatus::Running => "running",
+			JobStatus::Done => "done",
+			JobStatus::Failed => "failed",
+			JobStatus::Cancelled => "cancelled",
+		}
+	}
+
+	pub fn parse(s:&str)->Option<Self>{
+		match s{
+			"queued" => Some(JobStatus::Queued),
+			"running" => Some(JobStatus::Running),
+			"done" => Some(JobStatus::Done),
+			"failed" => Some(JobStatus::Failed),
+			"cancelled" => Some(JobStatus::Cancelled),
+			_ => None,
+		}
+	}
+}
+
+// last known job of a book , kept in the hash `queue:book:map:<id>`
+// times are unix seconds , 0 when not set yet
+#[derive(Debug, Clone, Serialize)]
+pub struct JobState{
+	pub status:JobStatus,
+	pub started_at:i64,
+	pub ended_at:i64,
+	pub chapters:u64,
+	pub bytes:u64,
+	pub error:Option<String>,
+	pub attempts:u32,
+	pub worker:Option<String>,
+	// fencing token of the lock the last attempt ran under
+	pub fence:u64,
+	// attempts that found the book locked by someone else , and the last holder
+	pub contention:u32,
+	pub locked_by:Option<String>,
+	// when the source file was removed , 0 while it exists
+	pub orphaned_at:i64,
+}
+
+// something that happened to a book , kept in `queue:book:history:<id>`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry{
+	pub at:i64,
+	pub event:String,
+	pub detail:String,
+}
+
+pub fn now_secs()->i64{
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// identifies this process in job state , `<hostname>:<pid>`
+pub fn worker_id()->String{
+	format!("{}:{}",gethostname::gethostname().to_string_lossy(),std::process::id())
+}
+
+// job state is bookkeeping , a failed write must not fail the job
+pub fn log_state(book_id:i32, result:Result<(),anyhow::Error>){
+	if let Err(e) = result{
+		warn!("Update job state of book {} err: {:?}",book_id,e);
+	}
+}
+
+pub struct JobStateClient{
+	conn: RedisConn,
+	keys: Keys,
+}
+
+#[allow(dead_code)]
+impl JobStateClient{
+
+	pub async fn new(redis_client: Arc<RedisClient>) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		Ok(Self { conn, keys: redis_client.keys().clone() })
+	}
+
+	fn key(&self, id:i32)->String{
+		self.keys.id(PREFIX_QUEUE_BOOK_STATE,id)
+	}
+
+	// a running book stays running , here or on another instance , its task runs after it
+	pub async fn queued(&mut self, id:i32)->Result<(),anyhow::Error>{
+		Script::new(QUEUED_SCRIPT)
+			.key(self.key(id))
+			.arg(JobStatus::Running.as_str())
+			.arg(JobStatus::Queued.as_str())
+			.invoke_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// attempt `attempt` of a job starts under lock `fence` , counters and the last error are reset
+	// attempts count the tries of this job , not of every job the book had
+	pub async fn running(&mut self, id:i32, worker:&str, fence:u64, attempt:u32)->Result<(),anyhow::Error>{
+		let key = self.key(id);
+		redis::pipe()
+			.cmd("HSET").arg(&key)
+				.arg("status").arg(JobStatus::Running.as_str())
+				.arg("started_at").arg(now_secs())
+				.arg("ended_at").arg(0)
+				.arg("chapters").arg(0)
+				.arg("bytes").arg(0)
+				.arg("worker").arg(worker)
+				.arg("fence").arg(fence)
+				.arg("attempts").arg(attempt)
+			.cmd("HDEL").arg(&key).arg("error")
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// the book was locked by `holder` when an attempt wanted to start
+	pub async fn contended(&mut self, id:i32, holder:Option<&str>)->Result<(),anyhow::Error>{
+		let key = self.key(id);
+		redis::pipe()
+			.cmd("HINCRBY").arg(&key).arg("contention").arg(1)
+			.cmd("HSET").arg(&key).arg("locked_by").arg(holder.unwrap_or(""))
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn history(&mut self, id:i32, event:&str, detail:&str)->Result<(),anyhow::Error>{
+		let key = self.keys.id(PREFIX_QUEUE_BOOK_HISTORY,id);
+		let entry = HistoryEntry{ at:now_secs(), event:event.to_string(), detail:detail.to_string() };
+		redis::pipe()
+			.cmd("LPUSH").arg(&key).arg(serde_json::to_string(&entry)?)
+			.cmd("LTRIM").arg(&key).arg(0).arg(HISTORY_LEN - 1)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn get_history(&mut self, id:i32)->Result<Vec<HistoryEntry>,anyhow::Error>{
+		let items:Vec<String> = cmd("LRANGE")
+			.arg(self.keys.id(PREFIX_QUEUE_BOOK_HISTORY,id))
+			.arg(0)
+			.arg(-1)
+			.query_async(&mut self.conn).await?;
+		Ok(items.iter().filter_map(|item| serde_json::from_str(item).ok()).collect())
+	}
+
+	// the source of the book is gone , its output no longer has a source
+	// the hash and the set may sit on different cluster nodes , so they are written one by one
+	pub async fn orphaned(&mut self, id:i32)->Result<(),anyhow::Error>{
+		cmd("HSET").arg(self.key(id)).arg("orphaned_at").arg(now_secs())
+			.query_async::<_, ()>(&mut self.conn).await?;
+		cmd("SADD").arg(self.keys.key(PREFIX_QUEUE_BOOK_ORPHANED)).arg(id)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// an uploaded source file no book points at , kept for operators
+	pub async fn add_unmatched(&mut self, name:&str)->Result<(),anyhow::Error>{
+		cmd("SADD")
+			.arg(self.keys.key(PREFIX_QUEUE_BOOK_UNMATCHED))
+			.arg(name)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn remove_unmatched(&mut self, name:&str)->Result<(),anyhow::Error>{
+		cmd("SREM")
+			.arg(self.keys.key(PREFIX_QUEUE_BOOK_UNMATCHED))
+			.arg(name)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// a source for the book showed up again
+	pub async fn adopted(&mut self, id:i32)->Result<(),anyhow::Error>{
+		cmd("HDEL").arg(self.key(id)).arg("orphaned_at")
+			.query_async::<_, ()>(&mut self.conn).await?;
+		cmd("SREM").arg(self.keys.key(PREFIX_QUEUE_BOOK_ORPHANED)).arg(id)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn progress(&mut self, id:i32, chapters:u64, bytes:u64)->Result<(),anyhow::Error>{
+		cmd("HSET")
+			.arg(self.key(id))
+			.arg("chapters").arg(chapters)
+			.arg("bytes").arg(bytes)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	// tasks that never started were cancelled , the counts of the last run are kept
+	pub async fn withdrawn(&mut self, id:i32)->Result<(),anyhow::Error>{
+		cmd("HSET")
+			.arg(self.key(id))
+			.arg("status").arg(JobStatus::Cancelled.as_str())
+			.arg("ended_at").arg(now_secs())
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn finished(&mut self, id:i32, status:JobStatus, chapters:u64, bytes:u64, error:Option<&str>)->Result<(),anyhow::Error>{
+		let key = self.key(id);
+		let mut pipe = redis::pipe();
+		pipe.cmd("HSET").arg(&key)
+			.arg("status").arg(status.as_str())
+			.arg("ended_at").arg(now_secs())
+			.arg("chapters").arg(chapters)
+			.arg("bytes").arg(bytes);
+		match error{
+			Some(e) => pipe.cmd("HSET").arg(&key).arg("error").arg(e),
+			None => pipe.cmd("HDEL").arg(&key).arg("error"),
+		};
+		pipe.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn get(&mut self, id:i32)->Result<Option<JobState>,anyhow::Error>{
+		let map:HashMap<String,String> = cmd("HGETALL").arg(self.key(id)).query_async(&mut self.conn).await?;
+		let status = match map.get("status").and_then(|s| JobStatus::parse(s)){
+			Some(status) => status,
+			None => return Ok(None),
+		};
+		let num = |field:&str| map.get(field).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+		Ok(Some(JobState{
+			status,
+			started_at: num("started_at") as i64,
+			ended_at: num("ended_at") as i64,
+			chapters: num("chapters"),
+			bytes: num("bytes"),
+			error: map.get("error").cloned(),
+			attempts: num("attempts") as u32,
+			worker: map.get("worker").cloned(),
+			fence: num("fence"),
+			contention: num("contention") as u32,
+			locked_by: map.get("locked_by").filter(|v| !v.is_empty()).cloned(),
+			orphaned_at: num("orphaned_at") as i64,
+		}))
+	}
+}
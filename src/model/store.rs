@@ -0,0 +1,252 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use log::warn;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::ops::Bound;
+use std::sync::Mutex;
+
+use crate::model::book::{field_path, renamed_source_url, set_path, Book};
+use crate::model::task::BookRef;
+
+// where books are kept , `BookRedisClient` , `SqliteBookStore` or `MemoryBookStore`
+// book ids are positive , `scan` cursors of the non redis stores are the last id returned
+#[allow(dead_code)]
+#[async_trait]
+pub trait BookStore: Debug + Send + Sync {
+	async fn get(&self, id: i32) -> Result<Option<Book>>;
+
+	async fn get_by_uuid(&self, uuid: &str) -> Result<Option<Book>>;
+
+	// `name` is a file name in input_dir , `.txt` is added when missing
+	async fn get_by_source(&self, name: &str) -> Result<Option<Book>>;
+
+	// the book is stored under its id , which must be set
+	async fn set(&self, book: &Book) -> Result<()>;
+
+	// `field` is a JSON path like `$.source_url` , the book must exist
+	async fn update(&self, id: i32, field: &str, value: serde_json::Value) -> Result<()>;
+
+	// ids of every book , each once
+	async fn list(&self) -> Result<Vec<i32>>;
+
+	// one batch of ids , start with cursor 0 , the walk is done when 0 comes back
+	async fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<i32>)>;
+
+	// point the book at a renamed source file
+	async fn rename_source(&self, id: i32, old: &str, new: &str) -> Result<()>;
+
+	// look a book up by id , uuid or source name , with the book id
+	async fn find(&self, book: &BookRef) -> Result<Option<(i32, Book)>> {
+		let found = match book {
+			BookRef::Id(id) => self.get(*id).await?.map(|b| (*id, b)),
+			BookRef::Uuid(uuid) => self.get_by_uuid(uuid).await?.and_then(|b| b.id.map(|id| (id, b))),
+			BookRef::Source(name) => self.get_by_source(name).await?.and_then(|b| b.id.map(|id| (id, b))),
+		};
+		if found.is_none() {
+			warn!("Book not found: {:?}", book);
+		}
+		Ok(found)
+	}
+}
+
+// source names are looked up with their extension
+pub fn source_file(name: &str) -> String {
+	if name.contains(".txt") { name.to_string() } else { format!("{}.txt", name) }
+}
+
+// books held as json in memory , nothing survives a restart , for tests and trying things out
+#[derive(Debug, Default)]
+pub struct MemoryBookStore {
+	books: Mutex<BTreeMap<i32, serde_json::Value>>,
+}
+
+impl MemoryBookStore {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn find_by(&self, matches: impl Fn(&Book) -> bool) -> Result<Option<Book>> {
+		for json in self.books.lock().unwrap().values() {
+			let book: Book = serde_json::from_value(json.clone())?;
+			if matches(&book) {
+				return Ok(Some(book));
+			}
+		}
+		Ok(None)
+	}
+}
+
+#[async_trait]
+impl BookStore for MemoryBookStore {
+	async fn get(&self, id: i32) -> Result<Option<Book>> {
+		let json = self.books.lock().unwrap().get(&id).cloned();
+		Ok(json.map(serde_json::from_value).transpose()?)
+	}
+
+	async fn get_by_uuid(&self, uuid: &str) -> Result<Option<Book>> {
+		self.find_by(|book| book.uuid == uuid)
+	}
+
+	async fn get_by_source(&self, name: &str) -> Result<Option<Book>> {
+		let name = source_file(name);
+		self.find_by(|book| book.source_name().as_deref() == Some(name.as_str()))
+	}
+
+	async fn set(&self, book: &Book) -> Result<()> {
+		let id = book.id.context("book has no id")?;
+		self.books.lock().unwrap().insert(id, serde_json::to_value(book)?);
+		Ok(())
+	}
+
+	async fn update(&self, id: i32, field: &str, value: serde_json::Value) -> Result<()> {
+		let path = field_path(field)?;
+		let mut books = self.books.lock().unwrap();
+		let mut doc = books.get(&id).cloned().with_context(|| format!("book {} not found", id))?;
+		set_path(&mut doc, &path, value)?;
+		// a change that no longer reads as a book is refused
+		serde_json::from_value::<Book>(doc.clone())?;
+		books.insert(id, doc);
+		Ok(())
+	}
+
+	async fn list(&self) -> Result<Vec<i32>> {
+		Ok(self.books.lock().unwrap().keys().copied().collect())
+	}
+
+	async fn scan(&self, cursor: u64, count: usize) -> Result<(u64, Vec<i32>)> {
+		let count = count.max(1);
+		let ids: Vec<i32> = self.books.lock().unwrap()
+			.range((Bound::Excluded(cursor as i32), Bound::Unbounded))
+			.take(count)
+			.map(|(id, _)| *id)
+			.collect();
+		let next = if ids.len() < count { 0 } else { ids.last().map(|id| *id as u64).unwrap_or(0) };
+		Ok((next, ids))
+	}
+
+	async fn rename_source(&self, id: i32, _old: &str, new: &str) -> Result<()> {
+		if let Some(source) = self.get(id).await?.and_then(|book| book.source_url) {
+			self.update(id, "$.source_url", renamed_source_url(&source, new).into()).await?;
+		}
+		Ok(())
+	}
+}
+
+// the same checks run against every store that has no server behind it
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use serde_json::json;
+
+	pub(crate) fn book(id: i32, source: &str) -> Book {
+		serde_json::from_value(json!({
+			"id": id,
+			"uuid": format!("uuid-{}", id),
+			"name": format!("book {}", id),
+			"created_at": 1,
+			"updated_at": 1,
+			"deleted_at": 0,
+			"category_id": 1,
+			"source_url": format!("http://files/books/{}", source),
+			"start_count": 10,
+		}))
+		.unwrap()
+	}
+
+	// every id from a full walk , in the order they came back
+	async fn walk(store: &dyn BookStore, count: usize) -> Vec<i32> {
+		let (mut cursor, mut ids) = (0, Vec::new());
+		for _ in 0..100 {
+			let (next, batch) = store.scan(cursor, count).await.unwrap();
+			ids.extend(batch);
+			if next == 0 {
+				return ids;
+			}
+			cursor = next;
+		}
+		panic!("scan did not come back to cursor 0");
+	}
+
+	pub(crate) async fn check_store(store: &dyn BookStore) {
+		assert!(store.get(1).await.unwrap().is_none());
+		assert_eq!(store.scan(0, 10).await.unwrap(), (0, vec![]));
+
+		let mut no_id = book(1, "a.txt");
+		no_id.id = None;
+		assert!(store.set(&no_id).await.is_err());
+
+		for (id, source) in [(3, "c.txt"), (1, "a.txt"), (2, "b.txt"), (5, "e.txt")] {
+			store.set(&book(id, source)).await.unwrap();
+		}
+		let got = store.get(1).await.unwrap().unwrap();
+		assert_eq!((got.id, got.name.as_str(), got.start_count), (Some(1), "book 1", Some(10)));
+		assert_eq!(store.get_by_uuid("uuid-2").await.unwrap().unwrap().id, Some(2));
+		assert!(store.get_by_uuid("uuid-9").await.unwrap().is_none());
+		assert_eq!(store.get_by_source("c").await.unwrap().unwrap().id, Some(3));
+		assert_eq!(store.get_by_source("c.txt").await.unwrap().unwrap().id, Some(3));
+		assert_eq!(store.list().await.unwrap(), vec![1, 2, 3, 5]);
+
+		// set replaces the whole book
+		let mut renamed = book(2, "b.txt");
+		renamed.name = "other".to_string();
+		store.set(&renamed).await.unwrap();
+		assert_eq!(store.get(2).await.unwrap().unwrap().name, "other");
+
+		store.update(1, "$.name", json!("updated")).await.unwrap();
+		store.update(1, "$.start_count", json!(20)).await.unwrap();
+		let got = store.get(1).await.unwrap().unwrap();
+		assert_eq!((got.name.as_str(), got.start_count), ("updated", Some(20)));
+		assert!(store.update(9, "$.name", json!("x")).await.is_err());
+		assert!(store.update(1, "name", json!("x")).await.is_err());
+		assert!(store.update(1, "$.keywords[0]", json!("x")).await.is_err());
+		assert!(store.update(1, "$.missing.name", json!("x")).await.is_err());
+		// a value of the wrong type is refused and the book is left as it was
+		assert!(store.update(1, "$.name", json!(5)).await.is_err());
+		assert_eq!(store.get(1).await.unwrap().unwrap().name, "updated");
+
+		store.rename_source(3, "c.txt", "d.txt").await.unwrap();
+		let got = store.get(3).await.unwrap().unwrap();
+		assert_eq!(got.source_url.as_deref(), Some("http://files/books/d.txt"));
+		assert!(store.get_by_source("c").await.unwrap().is_none());
+		assert_eq!(store.get_by_source("d").await.unwrap().unwrap().id, Some(3));
+
+		assert_eq!(store.find(&BookRef::Id(5)).await.unwrap().map(|(id, _)| id), Some(5));
+		assert_eq!(store.find(&BookRef::Uuid("uuid-2".into())).await.unwrap().map(|(id, _)| id), Some(2));
+		assert_eq!(store.find(&BookRef::Source("a.txt".into())).await.unwrap().map(|(id, _)| id), Some(1));
+		assert!(store.find(&BookRef::Id(4)).await.unwrap().is_none());
+	}
+
+	pub(crate) async fn check_scan(store: &dyn BookStore) {
+		for id in 1..=7 {
+			store.set(&book(id, &format!("{}.txt", id))).await.unwrap();
+		}
+		let (next, ids) = store.scan(0, 3).await.unwrap();
+		assert_eq!((next, ids), (3, vec![1, 2, 3]));
+		let (next, ids) = store.scan(3, 3).await.unwrap();
+		assert_eq!((next, ids), (6, vec![4, 5, 6]));
+		let (next, ids) = store.scan(6, 3).await.unwrap();
+		assert_eq!((next, ids), (0, vec![7]));
+		// a count of 0 still moves forward
+		assert_eq!(store.scan(0, 0).await.unwrap(), (1, vec![1]));
+		for count in [1, 2, 3, 7, 8, 100] {
+			assert_eq!(walk(store, count).await, (1..=7).collect::<Vec<_>>(), "count {}", count);
+		}
+	}
+
+	#[tokio::test]
+	async fn memory_store() {
+		check_store(&MemoryBookStore::new()).await;
+	}
+
+	#[tokio::test]
+	async fn memory_scan() {
+		check_scan(&MemoryBookStore::new()).await;
+	}
+
+	#[test]
+	fn source_file_adds_extension() {
+		assert_eq!(source_file("a"), "a.txt");
+		assert_eq!(source_file("a.txt"), "a.txt");
+	}
+}
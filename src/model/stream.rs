@@ -0,0 +1,136 @@
+use anyhow::Result;
+use redis::{cmd, FromRedisValue, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::{RedisClient, RedisConn};
+
+// field of a task entry holding the book id , e.g. `XADD stream:book:task * book_id 42`
+pub const STREAM_FIELD_BOOK_ID:&str = "book_id";
+// optional scheduling priority of a task entry , higher runs first
+pub const STREAM_FIELD_PRIORITY:&str = "priority";
+
+// one entry read from the task stream
+#[derive(Debug)]
+pub struct StreamTask{
+	pub id:String,
+	pub book_id:Option<i32>,
+	pub priority:Option<i64>,
+}
+
+// task stream read through a consumer group , every entry goes to one consumer
+// and stays pending until acked , entries idle too long are claimed by another one
+pub struct BookStream{
+	conn: RedisConn,
+	// XREADGROUP blocks its connection , so it gets one of its own , opened by the first `read`
+	blocking: Option<RedisConn>,
+	redis_client: Arc<RedisClient>,
+	key:String,
+	group:String,
+	consumer:String,
+	// where the next XAUTOCLAIM continues , back to `0-0` once the pending list was walked
+	claim_cursor:String,
+}
+
+fn parse_entries(value:&Value)->Result<Vec<StreamTask>,anyhow::Error>{
+	let entries:Vec<(String,Vec<String>)> = FromRedisValue::from_redis_value(value)?;
+	Ok(entries.into_iter().map(|(id,fields)|{
+		let field = |name:&str| fields.chunks(2)
+			.find(|kv| kv.len() == 2 && kv[0] == name)
+			.map(|kv| kv[1].clone());
+		let book_id = field(STREAM_FIELD_BOOK_ID).and_then(|v| v.parse::<i32>().ok());
+		let priority = field(STREAM_FIELD_PRIORITY).and_then(|v| v.parse::<i64>().ok());
+		StreamTask{ id, book_id, priority }
+	}).collect())
+}
+
+impl BookStream{
+
+	pub async fn new(redis_client: Arc<RedisClient>, key:&str, group:&str, consumer:&str) -> Result<Self, anyhow::Error> {
+		let conn = redis_client.get_manager().await?;
+		let key = redis_client.keys().key(key);
+		Ok(Self { conn, blocking:None, redis_client, key, group:group.to_string(), consumer:consumer.to_string(), claim_cursor:"0-0".to_string() })
+	}
+
+	// create the stream and group if missing , an existing group is kept
+	pub async fn ensure_group(&mut self)->Result<(),anyhow::Error>{
+		let rst:redis::RedisResult<()> = cmd("XGROUP")
+			.arg("CREATE")
+			.arg(&self.key)
+			.arg(&self.group)
+			.arg("$")
+			.arg("MKSTREAM")
+			.query_async(&mut self.conn).await;
+		match rst{
+			Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+			other => Ok(other?),
+		}
+	}
+
+	// new entries for this consumer , waits up to `block`
+	pub async fn read(&mut self, count:usize, block:Duration)->Result<Vec<StreamTask>,anyhow::Error>{
+		let mut read = cmd("XREADGROUP");
+		read.arg("GROUP")
+			.arg(&self.group)
+			.arg(&self.consumer)
+			.arg("COUNT")
+			.arg(count)
+			.arg("BLOCK")
+			.arg(block.as_millis() as u64)
+			.arg("STREAMS")
+			.arg(&self.key)
+			.arg(">");
+		let blocking = match self.blocking.as_mut(){
+			Some(conn) => conn,
+			None => self.blocking.insert(self.redis_client.get_connection().await?),
+		};
+		let reply:Option<Vec<(String,Value)>> = read.query_async(blocking).await?;
+		let mut tasks = Vec::new();
+		for (_,entries) in reply.unwrap_or_default(){
+			tasks.extend(parse_entries(&entries)?);
+		}
+		Ok(tasks)
+	}
+
+	// take over entries other consumers left pending longer than `min_idle`
+	// each call continues after the entries the last one looked at
+	pub async fn autoclaim(&mut self, min_idle:Duration, count:usize)->Result<Vec<StreamTask>,anyhow::Error>{
+		let reply:Vec<Value> = cmd("XAUTOCLAIM")
+			.arg(&self.key)
+			.arg(&self.group)
+			.arg(&self.consumer)
+			.arg(min_idle.as_millis() as u64)
+			.arg(&self.claim_cursor)
+			.arg("COUNT")
+			.arg(count)
+			.query_async(&mut self.conn).await?;
+		if let Some(cursor) = reply.first(){
+			self.claim_cursor = FromRedisValue::from_redis_value(cursor)?;
+		}
+		match reply.get(1){
+			Some(entries) => parse_entries(entries),
+			None => Ok(Vec::new()),
+		}
+	}
+
+	// reset the idle time of an entry this consumer still works on
+	pub async fn touch(&mut self, id:&str)->Result<(),anyhow::Error>{
+		cmd("XCLAIM")
+			.arg(&self.key)
+			.arg(&self.group)
+			.arg(&self.consumer)
+			.arg(0)
+			.arg(id)
+			.arg("JUSTID")
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+
+	pub async fn ack(&mut self, id:&str)->Result<(),anyhow::Error>{
+		cmd("XACK")
+			.arg(&self.key)
+			.arg(&self.group)
+			.arg(id)
+			.query_async::<_, ()>(&mut self.conn).await?;
+		Ok(())
+	}
+}
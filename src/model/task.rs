@@ -0,0 +1,181 @@
+use anyhow::{anyhow, bail, Context, Result};
+use redis::cmd;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use crate::config::RedisClient;
+
+// version of the task message schema this build understands
+pub const TASK_MESSAGE_VERSION:u32 = 1;
+
+// a book addressed by its id , uuid or source file name
+// e.g. `{"id":42}` , `{"uuid":"..."}` , `{"source":"name.txt"}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookRef{
+	Id(i32),
+	Uuid(String),
+	Source(String),
+}
+
+// what a task message asks for , tagged by `op`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum TaskOp{
+	// split up to the book's start_count , existing chapters are kept
+	Process{ book:BookRef },
+	// split again , `force` rewrites chapters that already exist
+	Reprocess{
+		book:BookRef,
+		#[serde(default)]
+		force:bool,
+	},
+	// write chapters `from..=to` only , existing ones are rewritten
+	SplitRange{ book:BookRef, from:usize, to:usize },
+	// remove every chapter written for the book
+	DeleteOutput{ book:BookRef },
+	Cancel{ book:BookRef },
+	// answered with a `Pong` on `reply_to` , only logged without it , the channel is used as given , without the key prefix
+	Ping{
+		#[serde(default)]
+		reply_to:Option<String>,
+	},
+	// dead letters of one book , or all of them without `book`
+	RequeueDead{
+		#[serde(default)]
+		book:Option<BookRef>,
+	},
+	Exit,
+}
+
+// a message on the task channel
+// `{"v":1,"op":"reprocess","book":{"uuid":"..."},"force":true,"priority":5000000}`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskMessage{
+	pub v:u32,
+	#[serde(flatten)]
+	pub op:TaskOp,
+	// replaces the book's own priority for the tasks this message queues , see `Book::priority`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub priority:Option<i64>,
+}
+
+impl TaskMessage{
+	// JSON messages must carry a supported `v`
+	// the plain forms `<book_id>` , `cancel:<book_id>` , `requeue-dead[:<book_id>]`
+	// and `exit` are still accepted as version 1
+	pub fn parse(payload:&str)->Result<Self,anyhow::Error>{
+		let payload = payload.trim();
+		if payload.starts_with('{'){
+			let msg:TaskMessage = serde_json::from_str(payload).context("invalid task message")?;
+			if msg.v != TASK_MESSAGE_VERSION{
+				bail!("unsupported task message version {}",msg.v);
+			}
+			return Ok(msg);
+		}
+		let op = Self::parse_plain(payload).ok_or_else(|| anyhow!("unknown task message"))?;
+		Ok(Self{ v:TASK_MESSAGE_VERSION, op, priority:None })
+	}
+
+	fn parse_plain(payload:&str)->Option<TaskOp>{
+		let id = |s:&str| s.trim().parse::<i32>().ok().map(BookRef::Id);
+		if payload == "exit"{
+			return Some(TaskOp::Exit);
+		}
+		if let Some(rest) = payload.strip_prefix("cancel:"){
+			return id(rest).map(|book| TaskOp::Cancel{ book });
+		}
+		if let Some(rest) = payload.strip_prefix("requeue-dead"){
+			return match rest.strip_prefix(':'){
+				Some(rest) => id(rest).map(|book| TaskOp::RequeueDead{ book:Some(book) }),
+				None if rest.is_empty() => Some(TaskOp::RequeueDead{ book:None }),
+				None => None,
+			};
+		}
+		id(payload).map(|book| TaskOp::Process{ book })
+	}
+}
+
+// answer to a ping
+#[derive(Debug, Clone, Serialize)]
+pub struct Pong{
+	pub v:u32,
+	pub op:&'static str,
+	pub worker:String,
+	// books being split right now
+	pub running:usize,
+	pub at:i64,
+}
+
+impl Pong{
+	pub async fn publish(&self, redis_client:Arc<RedisClient>, channel:&str)->Result<(),anyhow::Error>{
+		let mut conn = redis_client.get_manager().await?;
+		cmd("PUBLISH")
+			.arg(channel)
+			.arg(serde_json::to_string(self)?)
+			.query_async::<_, ()>(&mut conn).await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn op(payload:&str)->TaskOp{
+		TaskMessage::parse(payload).unwrap().op
+	}
+
+	#[test]
+	fn plain_forms_are_version_1(){
+		assert_eq!(TaskMessage::parse(" 42\n").unwrap(),TaskMessage{ v:1, op:TaskOp::Process{ book:BookRef::Id(42) }, priority:None });
+		assert_eq!(op("exit"),TaskOp::Exit);
+		assert_eq!(op("cancel:7"),TaskOp::Cancel{ book:BookRef::Id(7) });
+		assert_eq!(op("requeue-dead"),TaskOp::RequeueDead{ book:None });
+		assert_eq!(op("requeue-dead:7"),TaskOp::RequeueDead{ book:Some(BookRef::Id(7)) });
+	}
+
+	#[test]
+	fn bad_plain_forms_are_rejected(){
+		for payload in ["","abc","cancel:","cancel:x","cancel 7","requeue-dead:","requeue-deadx","requeue-dead:x","Exit","42a"]{
+			assert!(TaskMessage::parse(payload).is_err(),"{:?}",payload);
+		}
+	}
+
+	#[test]
+	fn json_messages_are_accepted(){
+		assert_eq!(op(r#"{"v":1,"op":"process","book":{"id":42}}"#),TaskOp::Process{ book:BookRef::Id(42) });
+		assert_eq!(op(r#"{"v":1,"op":"reprocess","book":{"uuid":"u"}}"#),TaskOp::Reprocess{ book:BookRef::Uuid("u".into()), force:false });
+		assert_eq!(op(r#"{"v":1,"op":"split-range","book":{"source":"a.txt"},"from":2,"to":5}"#),
+			TaskOp::SplitRange{ book:BookRef::Source("a.txt".into()), from:2, to:5 });
+		assert_eq!(op(r#"{"v":1,"op":"delete-output","book":{"id":1}}"#),TaskOp::DeleteOutput{ book:BookRef::Id(1) });
+		assert_eq!(op(r#"{"v":1,"op":"ping"}"#),TaskOp::Ping{ reply_to:None });
+		assert_eq!(op(r#"{"v":1,"op":"ping","reply_to":"pongs"}"#),TaskOp::Ping{ reply_to:Some("pongs".into()) });
+		assert_eq!(op(r#"{"v":1,"op":"requeue-dead"}"#),TaskOp::RequeueDead{ book:None });
+		assert_eq!(op(r#"{"v":1,"op":"exit"}"#),TaskOp::Exit);
+		let msg = TaskMessage::parse(r#"{"v":1,"op":"reprocess","book":{"id":3},"force":true,"priority":5000000}"#).unwrap();
+		assert_eq!(msg,TaskMessage{ v:1, op:TaskOp::Reprocess{ book:BookRef::Id(3), force:true }, priority:Some(5_000_000) });
+	}
+
+	#[test]
+	fn bad_json_messages_are_rejected(){
+		for payload in [
+			r#"{"op":"exit"}"#,
+			r#"{"v":2,"op":"exit"}"#,
+			r#"{"v":1}"#,
+			r#"{"v":1,"op":"unknown"}"#,
+			r#"{"v":1,"op":"process"}"#,
+			r#"{"v":1,"op":"process","book":{"name":"a"}}"#,
+			r#"{"v":1,"op":"process","book":{"id":"42"}}"#,
+			r#"{"v":1,"op":"split-range","book":{"id":1},"from":2}"#,
+			r#"{"v":1,"op":"exit""#,
+		]{
+			assert!(TaskMessage::parse(payload).is_err(),"{}",payload);
+		}
+	}
+
+	#[test]
+	fn messages_read_back_as_written(){
+		let msg = TaskMessage{ v:1, op:TaskOp::SplitRange{ book:BookRef::Uuid("u".into()), from:1, to:3 }, priority:Some(-1) };
+		assert_eq!(TaskMessage::parse(&serde_json::to_string(&msg).unwrap()).unwrap(),msg);
+	}
+}
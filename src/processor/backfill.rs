@@ -0,0 +1,136 @@
+use anyhow::Result;
+use log::{info, warn};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+use crate::config::BackfillConfig;
+use crate::model::book::{Book, BookRedisClient};
+use crate::model::manifest::{source_hash, source_stat, ManifestClient};
+use super::pool::{BookTask, WorkerPool};
+use super::processor::FileProcessor;
+use super::splitter::SplitOptions;
+
+const MIN_PACE: Duration = Duration::from_micros(1);
+
+// why a book is split again on startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Check {
+	// no full split on record
+	New,
+	// the source or start_count differs from the last full split
+	Changed,
+	// chapters of the last full split are gone
+	Incomplete,
+	Complete,
+}
+
+// compare the book's manifest with its source , the source is only hashed when its
+// mtime moved but its size did not , a matching hash refreshes the manifest
+async fn check(processor: &FileProcessor, manifests: &mut ManifestClient, id: i32, book: &Book, file: &str, path: &Path) -> Result<Check> {
+	let mut manifest = match manifests.get(id).await? {
+		Some(manifest) => manifest,
+		None => return Ok(Check::New),
+	};
+	let (size, mtime) = source_stat(path)?;
+	if size != manifest.size || book.start_count != Some(manifest.stop) {
+		return Ok(Check::Changed);
+	}
+	if mtime != manifest.mtime {
+		let hash_path = path.to_path_buf();
+		let hash = tokio::task::spawn_blocking(move || source_hash(&hash_path)).await??;
+		if hash != manifest.hash {
+			return Ok(Check::Changed);
+		}
+		manifest.mtime = mtime;
+		manifests.set(id, &manifest).await?;
+	}
+	if (processor.output_chapters(file) as u64) < manifest.chapters {
+		return Ok(Check::Incomplete);
+	}
+	Ok(Check::Complete)
+}
+
+// queue every stored book that is new , changed or incomplete , walking the book keys with SCAN
+// the cursor is saved once every book of a batch was split , so a restart continues with
+// the next batch instead of the first one and loses no book , it is cleared once the walk is done
+pub async fn backfill(processor: Arc<FileProcessor>, pool: WorkerPool, config: BackfillConfig) -> Result<usize> {
+	// the cursor is kept in redis whatever store holds the books
+	let mut cursors = BookRedisClient::new(processor.redis_client.clone()).await?;
+	let mut manifests = ManifestClient::new(processor.redis_client.clone()).await?;
+	let mut cursor = cursors.get_backfill_cursor().await?.unwrap_or(0);
+	if cursor != 0 {
+		info!("Resume backfill at cursor {}", cursor);
+	}
+	// a rate past a billion would make a zero period
+	let mut pace = interval((Duration::from_secs(1) / config.rate.max(1)).max(MIN_PACE));
+	pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+	let (mut queued, mut complete) = (0, 0);
+	loop {
+		let (next, ids) = processor.books.scan(cursor, config.batch).await?;
+		let mut batch = Vec::new();
+		for id in ids {
+			let book = match processor.books.get(id).await? {
+				Some(book) => book,
+				None => continue,
+			};
+			let file = match book.source_name() {
+				Some(file) => file,
+				None => {
+					warn!("Book has no source , skipped: {:?}", id);
+					continue;
+				}
+			};
+			// books without start_count are never split , they would never get a manifest either
+			let stop = match book.start_count {
+				Some(stop) => stop,
+				None => {
+					warn!("Book has no start_count , skipped: {:?}", id);
+					continue;
+				}
+			};
+			let path = processor.input_dir.join(&file);
+			if !path.is_file() {
+				warn!("Book source file missing , skipped: {:?}", path);
+				continue;
+			}
+			let mut split = SplitOptions::until(stop);
+			match check(&processor, &mut manifests, id, &book, &file, &path).await {
+				Ok(Check::Complete) => {
+					complete += 1;
+					continue;
+				}
+				// chapters of an older source would be kept otherwise
+				Ok(Check::Changed) => split.overwrite = true,
+				Ok(reason) => info!("Book {} is {:?}", id, reason),
+				Err(e) => warn!("Check book {} err , queued: {:?}", id, e),
+			}
+			pace.tick().await;
+			let (done, settled) = oneshot::channel();
+			pool.submit(BookTask {
+				book_id: id,
+				uuid: book.uuid.clone(),
+				path,
+				name: file,
+				split,
+				priority: book.priority(),
+				done: Some(done),
+			}).await?;
+			batch.push(settled);
+			queued += 1;
+		}
+		// a book replaced by a newer task of it counts as settled , that task covers it
+		for settled in batch {
+			let _ = settled.await;
+		}
+		if next == 0 {
+			break;
+		}
+		cursor = next;
+		cursors.set_backfill_cursor(Some(cursor)).await?;
+	}
+	cursors.set_backfill_cursor(None).await?;
+	info!("Backfill queued {} books , {} already complete", queued, complete);
+	Ok(queued)
+}
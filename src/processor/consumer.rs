@@ -0,0 +1,258 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+use tokio::time::{interval, sleep, Duration, Instant};
+
+use crate::config::{RetryPolicy, StreamConfig};
+use crate::model::queue::{BookQueue, Reserved};
+use crate::model::stream::{BookStream, StreamTask};
+use super::pool::{BookTask, WorkerPool};
+use super::processor::FileProcessor;
+use super::splitter::SplitOptions;
+
+// how long one BLMOVE / XREADGROUP waits for an item before looping
+const RESERVE_WAIT: Duration = Duration::from_secs(5);
+// entries read or claimed from the stream in one call
+const STREAM_BATCH: usize = 16;
+// takes source file names from the reliable queue and runs them on the pool
+// an item is acked once the pool settled it , failures are retried by the pool and
+// end in the dead letter list , an item of a dead worker is handed out again by
+// `requeue_expired` after the visibility timeout
+// redis errors are retried with backoff , it only returns once the pool stopped
+pub async fn consume_queue(processor: Arc<FileProcessor>, pool: WorkerPool, visibility: Duration) -> Result<()> {
+	let mut queue = None;
+	let mut retries = 0;
+	loop {
+		let (item, task) = match next_queue_task(&processor, &mut queue, visibility).await {
+			Ok(Some(next)) => next,
+			Ok(None) => continue,
+			Err(e) => {
+				retries += 1;
+				let delay = RetryPolicy::RECONNECT.delay(retries);
+				warn!("Queue consumer err , retry in {:?}: {:?}", delay, e);
+				sleep(delay).await;
+				continue;
+			}
+		};
+		retries = 0;
+		let (tx, rx) = oneshot::channel();
+		pool.submit(BookTask { done: Some(tx), ..task }).await?;
+		let processor = processor.clone();
+		tokio::spawn(async move {
+			if let Err(e) = settle(processor, item.clone(), rx, visibility).await {
+				error!("Queue item {:?} not settled: {:?}", item, e);
+			}
+		});
+	}
+}
+
+// reserve one item and find its book , items without a usable book are acked and dropped
+async fn next_queue_task(processor: &FileProcessor, queue: &mut Option<BookQueue>, visibility: Duration) -> Result<Option<(String, BookTask)>> {
+	let queue = match queue {
+		Some(queue) => queue,
+		None => queue.insert(BookQueue::new(processor.redis_client.clone()).await?),
+	};
+	let Reserved { item, priority } = match queue.reserve(visibility, RESERVE_WAIT).await? {
+		Some(reserved) => reserved,
+		None => return Ok(None),
+	};
+	let file = if item.contains(".txt") { item.clone() } else { format!("{}.txt", item) };
+	let (book_id, book) = match processor.books.get_by_source(&file).await? {
+		Some(book) => match book.id {
+			Some(book_id) => (book_id, book),
+			None => {
+				warn!("Queue item book has no id , dropped: {:?}", item);
+				queue.ack(&item).await?;
+				return Ok(None);
+			}
+		},
+		None => {
+			warn!("Queue item has no book , dropped: {:?}", item);
+			queue.ack(&item).await?;
+			return Ok(None);
+		}
+	};
+	let path = processor.input_dir.join(&file);
+	if !path.exists() {
+		warn!("Queue item source not found , dropped: {:?}", path);
+		queue.ack(&item).await?;
+		return Ok(None);
+	}
+	let task = BookTask {
+		book_id,
+		uuid: book.uuid.clone(),
+		path,
+		name: file,
+		split: SplitOptions::until(book.start_count.unwrap_or(0)),
+		priority: priority.unwrap_or_else(|| book.priority()),
+		done: None,
+	};
+	Ok(Some((item, task)))
+}
+
+// keep the lease alive while the book is split , then ack it
+async fn settle(processor: Arc<FileProcessor>, item: String, mut rx: oneshot::Receiver<Result<usize>>, visibility: Duration) -> Result<()> {
+	let mut queue = BookQueue::new(processor.redis_client.clone()).await?;
+	let mut heartbeat = interval(visibility / 2);
+	heartbeat.tick().await;
+	let result = loop {
+		tokio::select! {
+			result = &mut rx => break result,
+			_ = heartbeat.tick() => {
+				if let Err(e) = queue.extend(&item, visibility).await {
+					warn!("Extend lease of queue item {:?} err: {:?}", item, e);
+				}
+			}
+		}
+	};
+	// a newer task for the same book replaced this one ( Err ) , it covers the item
+	if let Ok(Err(e)) = result {
+		warn!("Queue item {:?} failed , dead lettered: {:?}", item, e);
+	}
+	queue.ack(&item).await?;
+	Ok(())
+}
+
+// periodically hand items of dead workers back to the queue
+pub async fn requeue_expired(processor: Arc<FileProcessor>, every: Duration) -> Result<()> {
+	let mut queue = BookQueue::new(processor.redis_client.clone()).await?;
+	loop {
+		sleep(every).await;
+		match queue.requeue_expired().await {
+			Ok(0) => {}
+			Ok(n) => info!("Requeued {} expired queue items", n),
+			Err(e) => error!("Requeue expired items err: {:?}", e),
+		}
+	}
+}
+
+// takes book ids from the task stream through a consumer group and runs them on the pool
+// entries are acked once the pool settled them , entries of a dead consumer stay
+// pending and are claimed again with XAUTOCLAIM after `claim_idle` , by this instance or another one
+// redis errors are retried with backoff , it only returns once the pool stopped
+pub async fn consume_stream(processor: Arc<FileProcessor>, pool: WorkerPool, config: StreamConfig) -> Result<()> {
+	let consumer = config.consumer_name();
+	let claim_idle = Duration::from_secs(config.claim_idle);
+	let mut stream = None;
+	let mut retries = 0;
+	let mut next_claim = Instant::now();
+	loop {
+		let entries = match next_stream_entries(&processor, &config, &consumer, &mut stream, &mut next_claim).await {
+			Ok(entries) => entries,
+			Err(e) => {
+				retries += 1;
+				let delay = RetryPolicy::RECONNECT.delay(retries);
+				warn!("Stream consumer err , retry in {:?}: {:?}", delay, e);
+				sleep(delay).await;
+				continue;
+			}
+		};
+		retries = 0;
+		let reader = match stream.as_mut() {
+			Some(reader) => reader,
+			None => continue,
+		};
+		for entry in entries {
+			let id = entry.id.clone();
+			// an entry that hit a redis error stays pending and is claimed again later
+			let task = match stream_task(&processor, reader, entry).await {
+				Ok(Some(task)) => task,
+				Ok(None) => continue,
+				Err(e) => {
+					warn!("Stream entry {:?} left pending: {:?}", id, e);
+					continue;
+				}
+			};
+			let (tx, rx) = oneshot::channel();
+			pool.submit(BookTask { done: Some(tx), ..task }).await?;
+			let processor = processor.clone();
+			let (config, consumer) = (config.clone(), consumer.clone());
+			tokio::spawn(async move {
+				if let Err(e) = settle_stream(processor, config, consumer, id.clone(), rx, claim_idle).await {
+					error!("Stream entry {:?} not settled: {:?}", id, e);
+				}
+			});
+		}
+	}
+}
+
+// idle entries of other consumers every `claim_idle / 2` , then new ones
+// the stream and its group are set up again after an error
+async fn next_stream_entries(processor: &FileProcessor, config: &StreamConfig, consumer: &str, stream: &mut Option<BookStream>, next_claim: &mut Instant) -> Result<Vec<StreamTask>> {
+	let claim_idle = Duration::from_secs(config.claim_idle);
+	let reader = match stream {
+		Some(reader) => reader,
+		None => {
+			let mut reader = BookStream::new(processor.redis_client.clone(), &config.key, &config.group, consumer).await?;
+			reader.ensure_group().await?;
+			info!("Reading {:?} as {:?} in group {:?}", config.key, consumer, config.group);
+			stream.insert(reader)
+		}
+	};
+	let mut entries = Vec::new();
+	if Instant::now() >= *next_claim {
+		entries = reader.autoclaim(claim_idle, STREAM_BATCH).await?;
+		if !entries.is_empty() {
+			info!("Claimed {} idle stream entries", entries.len());
+		}
+		*next_claim = Instant::now() + claim_idle / 2;
+	}
+	entries.extend(reader.read(STREAM_BATCH, RESERVE_WAIT).await?);
+	Ok(entries)
+}
+
+// the task of one entry , entries without a usable book are acked and dropped
+async fn stream_task(processor: &FileProcessor, stream: &mut BookStream, entry: StreamTask) -> Result<Option<BookTask>> {
+	let StreamTask { id, book_id, priority } = entry;
+	let book = match book_id {
+		Some(book_id) => processor.books.get(book_id).await?,
+		None => None,
+	};
+	let (book_id, book) = match (book_id, book) {
+		(Some(book_id), Some(book)) => (book_id, book),
+		_ => {
+			warn!("Stream entry {:?} has no book , dropped: {:?}", id, book_id);
+			stream.ack(&id).await?;
+			return Ok(None);
+		}
+	};
+	let file = book.source_url.as_deref().unwrap_or("").split('/').next_back().unwrap_or("").to_string();
+	let path = processor.input_dir.join(&file);
+	if file.is_empty() || !path.exists() {
+		warn!("Stream entry {:?} source not found , dropped: {:?}", id, path);
+		stream.ack(&id).await?;
+		return Ok(None);
+	}
+	Ok(Some(BookTask {
+		book_id,
+		uuid: book.uuid.clone(),
+		path,
+		name: file,
+		split: SplitOptions::until(book.start_count.unwrap_or(0)),
+		priority: priority.unwrap_or_else(|| book.priority()),
+		done: None,
+	}))
+}
+
+// keep the entry claimed while the book is split , then ack it
+async fn settle_stream(processor: Arc<FileProcessor>, config: StreamConfig, consumer: String, id: String, mut rx: oneshot::Receiver<Result<usize>>, claim_idle: Duration) -> Result<()> {
+	let mut stream = BookStream::new(processor.redis_client.clone(), &config.key, &config.group, &consumer).await?;
+	let mut heartbeat = interval(claim_idle / 2);
+	heartbeat.tick().await;
+	let result = loop {
+		tokio::select! {
+			result = &mut rx => break result,
+			_ = heartbeat.tick() => {
+				if let Err(e) = stream.touch(&id).await {
+					warn!("Touch stream entry {:?} err: {:?}", id, e);
+				}
+			}
+		}
+	};
+	if let Ok(Err(e)) = result {
+		warn!("Stream entry {:?} failed , dead lettered: {:?}", id, e);
+	}
+	stream.ack(&id).await?;
+	Ok(())
+}
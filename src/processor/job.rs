@@ -0,0 +1,53 @@
+use anyhow::Result;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+
+use super::splitter::Progress;
+
+// stops a running split before its next chapter
+#[derive(Debug, Clone)]
+pub struct Canceller(Arc<Progress>);
+
+impl Canceller {
+	pub fn cancel(&self) {
+		self.0.cancel();
+	}
+}
+
+// handle to a book split running on the blocking pool
+#[derive(Debug)]
+pub struct JobHandle {
+	pub name: String,
+	progress: Arc<Progress>,
+	task: JoinHandle<Result<usize>>,
+}
+
+impl JobHandle {
+	pub(crate) fn new(name: String, progress: Arc<Progress>, task: JoinHandle<Result<usize>>) -> Self {
+		Self { name, progress, task }
+	}
+
+	// (chapters written , bytes written) so far
+	pub fn progress(&self) -> (usize, u64) {
+		(
+			self.progress.chapters.load(Ordering::Relaxed),
+			self.progress.bytes.load(Ordering::Relaxed),
+		)
+	}
+
+	// indexes of the chapters this job created
+	pub fn changed_chapters(&self) -> Vec<usize> {
+		self.progress.written_chapters()
+	}
+
+	pub fn canceller(&self) -> Canceller {
+		Canceller(self.progress.clone())
+	}
+
+	// wait for the split , returns the number of chapters written
+	// cancel safe , must not be polled again once it returned
+	pub async fn join(&mut self) -> Result<usize> {
+		(&mut self.task).await?
+	}
+}
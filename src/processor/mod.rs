@@ -1,2 +1,11 @@
+#[allow(clippy::module_inception)]
 mod processor;
-pub use processor::FileProcessor;
\ No newline at end of file
+mod backfill;
+mod consumer;
+mod job;
+mod pool;
+pub mod splitter;
+pub use processor::{output_stem, FileProcessor};
+pub use pool::{BookTask, WorkerPool};
+pub use backfill::backfill;
+pub use consumer::{consume_queue, consume_stream, requeue_expired};
@@ -0,0 +1,744 @@
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{interval_at, sleep_until, Duration, Instant};
+
+use crate::config::{EventConfig, RetryPolicy, WatcherConfig};
+use crate::model::dead::{Attempt, DeadLetter, DeadLetterClient};
+use crate::model::event::{EventPublisher, JobEvent};
+use crate::model::lock::{BookLock, LockGuard, Locked};
+use crate::model::manifest::{source_hash, source_stat, Manifest, ManifestClient};
+use crate::model::state::{log_state, now_secs, worker_id, JobStateClient, JobStatus};
+use super::job::{Canceller, JobHandle};
+use super::processor::FileProcessor;
+use super::splitter::{Cancelled, Fence, SplitOptions};
+
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+// a split asks the lock fence again before writing once this long passed since it last did
+const FENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// a task for a locked book waits at least this long before it asks for the lock again
+const LOCKED_RETRY_MIN: Duration = Duration::from_millis(100);
+
+// one book to split
+#[derive(Debug)]
+pub struct BookTask {
+	pub book_id: i32,
+	pub uuid: String,
+	pub path: PathBuf,
+	pub name: String,
+	pub split: SplitOptions,
+	// higher runs first , see `Book::priority`
+	pub priority: i64,
+	// receives the result , dropped without a value when a newer task for the same book replaced this one
+	pub done: Option<oneshot::Sender<Result<usize>>>,
+}
+
+// runs up to `concurrent_limit` books at once , tasks wait in a queue of `queue_size` and
+// the highest priority one starts first , tasks of equal priority in arrival order
+// a book is never split twice at the same time , a task for a running book is
+// held back until that run ends ( only the newest one is kept )
+// every split runs under a redis lock of its book , so other instances do not split it too ,
+// a task for a book locked elsewhere waits for the lock to expire or be released and runs then ,
+// such a wait is no attempt
+// failed splits are retried by the retry policy , then moved to the dead letter list
+// a failed split waits for its retry without holding a slot
+#[derive(Debug, Clone)]
+pub struct WorkerPool {
+	tx: mpsc::UnboundedSender<Submitted>,
+	// free places in the queue , taken by `submit` and given back when a task leaves the queue
+	places: Arc<Semaphore>,
+	// a book whose waiting tasks are dropped , answered with how many there were
+	cancel_tx: mpsc::UnboundedSender<(i32, oneshot::Sender<usize>)>,
+	shared: Arc<Shared>,
+}
+
+// cancel handles of the books being split right now
+type Running = Mutex<HashMap<i32, Canceller>>;
+
+#[derive(Debug)]
+struct Shared {
+	processor: Arc<FileProcessor>,
+	running: Running,
+	events: EventConfig,
+	retry: RetryPolicy,
+	lock_ttl: Duration,
+}
+
+impl WorkerPool {
+	pub fn new(processor: Arc<FileProcessor>, config: &WatcherConfig) -> Self {
+		let (tx, rx) = mpsc::unbounded_channel();
+		let (cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+		let shared = Arc::new(Shared {
+			processor,
+			running: Running::default(),
+			events: config.events.clone(),
+			retry: config.retry.clone(),
+			lock_ttl: Duration::from_secs(config.lock_ttl.max(1)),
+		});
+		tokio::spawn(dispatch(shared.clone(), rx, cancel_rx, config.concurrent_limit.max(1)));
+		Self { tx, places: Arc::new(Semaphore::new(config.queue_size.max(1))), cancel_tx, shared }
+	}
+
+	// waits while the queue is full
+	pub async fn submit(&self, task: BookTask) -> Result<()> {
+		let place = self.places.clone().acquire_owned().await.map_err(|_| anyhow!("worker pool stopped"))?;
+		self.send(task, Some(place)).await
+	}
+
+	// queued at once even when the queue is full , for callers that must not wait ,
+	// such as the task channel whose cancel and exit messages would wait behind it
+	pub async fn submit_now(&self, task: BookTask) -> Result<()> {
+		self.send(task, None).await
+	}
+
+	async fn send(&self, task: BookTask, place: Option<OwnedSemaphorePermit>) -> Result<()> {
+		// recorded first so a fast worker's `running` is not overwritten
+		match JobStateClient::new(self.shared.processor.redis_client.clone()).await {
+			Ok(mut state) => log_state(task.book_id, state.queued(task.book_id).await),
+			Err(e) => log_state(task.book_id, Err(e)),
+		}
+		self.tx.send(Submitted { task, place }).map_err(|_| anyhow!("worker pool stopped"))
+	}
+
+	// ask the running split of a book to stop and drop its tasks waiting to run after it ,
+	// false when the book was neither running nor waiting
+	// a task whose producer still waits for a place in the queue is not reached
+	pub async fn cancel(&self, book_id: i32) -> bool {
+		let running = match self.shared.running.lock().unwrap_or_else(|e| e.into_inner()).get(&book_id) {
+			Some(canceller) => {
+				canceller.cancel();
+				true
+			}
+			None => false,
+		};
+		let (tx, rx) = oneshot::channel();
+		let dropped = match self.cancel_tx.send((book_id, tx)) {
+			Ok(()) => rx.await.unwrap_or(0),
+			Err(_) => 0,
+		};
+		if dropped > 0 {
+			info!("Dropped {} waiting tasks of book {}", dropped, book_id);
+		}
+		running || dropped > 0
+	}
+
+	pub fn is_running(&self, book_id: i32) -> bool {
+		self.shared.running.lock().unwrap_or_else(|e| e.into_inner()).contains_key(&book_id)
+	}
+
+	// how long a book lock lives without renewal
+	pub fn lock_ttl(&self) -> Duration {
+		self.shared.lock_ttl
+	}
+
+	// books being split right now
+	pub fn running_count(&self) -> usize {
+		self.shared.running.lock().unwrap_or_else(|e| e.into_inner()).len()
+	}
+
+	// submit dead letters again , of one book or all of them
+	pub async fn requeue_dead(&self, book_id: Option<i32>) -> Result<usize> {
+		let mut dead = DeadLetterClient::new(self.shared.processor.redis_client.clone()).await?;
+		let letters = dead.take(book_id).await?;
+		let count = letters.len();
+		for letter in letters {
+			info!("Requeue dead letter of book {} after {} attempts", letter.book_id, letter.attempts.len());
+			self.submit(BookTask {
+				book_id: letter.book_id,
+				uuid: letter.uuid,
+				path: PathBuf::from(letter.path),
+				name: letter.name,
+				split: letter.split,
+				priority: letter.priority,
+				done: None,
+			}).await?;
+		}
+		Ok(count)
+	}
+}
+
+// a task with the attempts it already made
+#[derive(Debug)]
+struct Job {
+	task: BookTask,
+	attempt: u32,
+	history: Vec<Attempt>,
+	// when the first attempt started
+	started: Option<Instant>,
+	// (chapters , bytes) of the attempt that got furthest
+	chapters: usize,
+	bytes: u64,
+	// chapters created by any attempt , a retry skips the ones written before it
+	changed: BTreeSet<usize>,
+}
+
+impl Job {
+	fn new(task: BookTask) -> Self {
+		Self { task, attempt: 0, history: Vec::new(), started: None, chapters: 0, bytes: 0, changed: BTreeSet::new() }
+	}
+
+	fn record(&mut self, split: &JobHandle) {
+		let (chapters, bytes) = split.progress();
+		if chapters >= self.chapters {
+			(self.chapters, self.bytes) = (chapters, bytes);
+		}
+		self.changed.extend(split.changed_chapters());
+	}
+}
+
+// a run that ended , with its job again when it is retried at the given time
+#[derive(Debug)]
+struct Done {
+	book_id: i32,
+	retry: Option<(Instant, Job)>,
+}
+
+// a task on its way to the queue , with its place in it when `submit` took one
+#[derive(Debug)]
+struct Submitted {
+	task: BookTask,
+	place: Option<OwnedSemaphorePermit>,
+}
+
+// a task waiting for a worker , ordered by priority then arrival
+// its place in the queue is given back once it leaves the heap
+#[derive(Debug)]
+struct Waiting {
+	seq: u64,
+	job: Job,
+	_place: Option<OwnedSemaphorePermit>,
+}
+
+impl Ord for Waiting {
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.job.task.priority.cmp(&other.job.task.priority).then_with(|| other.seq.cmp(&self.seq))
+	}
+}
+
+impl PartialOrd for Waiting {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl PartialEq for Waiting {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Waiting {}
+
+// how `dispatch` starts a job and records a book whose waiting tasks were cancelled ,
+// the pool splits under the redis lock , tests stand in without redis
+trait Runner: Send + 'static {
+	fn run(&self, job: Job, done: mpsc::UnboundedSender<Done>);
+	fn withdraw(&self, book_id: i32);
+}
+
+impl Runner for Arc<Shared> {
+	fn run(&self, job: Job, done: mpsc::UnboundedSender<Done>) {
+		tokio::spawn(run(self.clone(), job, done));
+	}
+
+	fn withdraw(&self, book_id: i32) {
+		tokio::spawn(withdraw(self.clone(), book_id));
+	}
+}
+
+// tasks are taken off the channel into a heap as soon as they arrive , so a late high
+// priority task overtakes the ones already waiting , `submit` bounds the heap
+// a failed attempt gives its slot back , the retry waits in `delayed` until its backoff
+// passed and then queues like any task , a newer task for the book replaces it
+// a cancelled book loses its deferred , delayed and waiting tasks
+async fn dispatch(runner: impl Runner, mut rx: mpsc::UnboundedReceiver<Submitted>, mut cancel_rx: mpsc::UnboundedReceiver<(i32, oneshot::Sender<usize>)>, limit: usize) {
+	let (done_tx, mut done_rx) = mpsc::unbounded_channel::<Done>();
+	let mut running: HashSet<i32> = HashSet::new();
+	let mut deferred: HashMap<i32, Job> = HashMap::new();
+	let mut delayed: Vec<(Instant, Job)> = Vec::new();
+	let mut waiting: BinaryHeap<Waiting> = BinaryHeap::new();
+	let mut seq = 0;
+	let mut open = true;
+	loop {
+		while running.len() < limit {
+			let job = match waiting.pop() {
+				Some(Waiting { job, .. }) => job,
+				None => break,
+			};
+			let book_id = job.task.book_id;
+			if running.contains(&book_id) {
+				info!("Book {} is already running , deferred", book_id);
+				deferred.insert(book_id, job);
+				continue;
+			}
+			running.insert(book_id);
+			runner.run(job, done_tx.clone());
+		}
+		if !open && running.is_empty() && waiting.is_empty() && delayed.is_empty() {
+			break;
+		}
+		let next_retry = delayed.iter().map(|(at, _)| *at).min();
+		tokio::select! {
+			Some(done) = done_rx.recv() => {
+				running.remove(&done.book_id);
+				if let Some(job) = deferred.remove(&done.book_id) {
+					seq += 1;
+					waiting.push(Waiting { seq, job, _place: None });
+				} else if let Some(retry) = done.retry {
+					delayed.push(retry);
+				}
+			}
+			_ = sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+				let now = Instant::now();
+				let (due, later): (Vec<_>, Vec<_>) = delayed.drain(..).partition(|(at, _)| *at <= now);
+				delayed = later;
+				for (_, job) in due {
+					seq += 1;
+					waiting.push(Waiting { seq, job, _place: None });
+				}
+			}
+			Some((book_id, reply)) = cancel_rx.recv() => {
+				let before = waiting.len() + delayed.len();
+				waiting.retain(|waiting| waiting.job.task.book_id != book_id);
+				delayed.retain(|(_, job)| job.task.book_id != book_id);
+				let dropped = before - waiting.len() - delayed.len() + usize::from(deferred.remove(&book_id).is_some());
+				// a running split records its own end
+				if dropped > 0 && !running.contains(&book_id) {
+					runner.withdraw(book_id);
+				}
+				let _ = reply.send(dropped);
+			}
+			submitted = rx.recv(), if open => {
+				match submitted {
+					Some(Submitted { task, place }) => {
+						delayed.retain(|(_, job)| job.task.book_id != task.book_id);
+						seq += 1;
+						waiting.push(Waiting { seq, job: Job::new(task), _place: place });
+					}
+					None => open = false,
+				}
+			}
+			else => break,
+		}
+	}
+}
+
+async fn withdraw(shared: Arc<Shared>, book_id: i32) {
+	match JobStateClient::new(shared.processor.redis_client.clone()).await {
+		Ok(mut state) => log_state(book_id, state.withdrawn(book_id).await),
+		Err(e) => log_state(book_id, Err(e)),
+	}
+}
+
+// one attempt at a book , a failure left to retry and a locked book go back to `dispatch` ,
+// otherwise the outcome is recorded and published
+async fn run(shared: Arc<Shared>, mut job: Job, done: mpsc::UnboundedSender<Done>) {
+	let book_id = job.task.book_id;
+	let redis_client = shared.processor.redis_client.clone();
+	let started = *job.started.get_or_insert_with(Instant::now);
+	let mut state = match JobStateClient::new(redis_client.clone()).await {
+		Ok(state) => Some(state),
+		Err(e) => {
+			log_state(book_id, Err(e));
+			None
+		}
+	};
+	job.attempt += 1;
+	let result = run_attempt(&shared, &mut job, state.as_mut()).await;
+	if let Err(e) = &result {
+		if !e.is::<Cancelled>() && !e.is::<Locked>() {
+			job.history.push(Attempt { attempt: job.attempt, at: now_secs(), error: format!("{:#}", e) });
+			if job.attempt < shared.retry.max_attempts {
+				let delay = shared.retry.delay(job.attempt);
+				warn!("Book {} failed attempt {} , retry in {:?}", book_id, job.attempt, delay);
+				shared.running.lock().unwrap_or_else(|e| e.into_inner()).remove(&book_id);
+				let _ = done.send(Done { book_id, retry: Some((Instant::now() + delay, job)) });
+				return;
+			}
+		}
+	}
+	// tried again once the lock is free , a holder that crashed leaves it until it expires
+	if let Some(locked) = result.as_ref().err().and_then(|e| e.downcast_ref::<Locked>()) {
+		job.attempt -= 1;
+		let delay = locked.expires_in.unwrap_or(shared.lock_ttl).clamp(LOCKED_RETRY_MIN, shared.lock_ttl);
+		info!("Task of book {} waits , {} , retry in {:?}", book_id, locked, delay);
+		shared.running.lock().unwrap_or_else(|e| e.into_inner()).remove(&book_id);
+		let _ = done.send(Done { book_id, retry: Some((Instant::now() + delay, job)) });
+		return;
+	}
+	let Job { task, history, chapters, bytes, changed, .. } = job;
+	let (status, error) = match &result {
+		Ok(_) => (JobStatus::Done, None),
+		Err(e) if e.is::<Cancelled>() => (JobStatus::Cancelled, None),
+		Err(e) => (JobStatus::Failed, Some(format!("{:#}", e))),
+	};
+	if let Some(state) = state.as_mut() {
+		log_state(book_id, state.finished(book_id, status, chapters as u64, bytes, error.as_deref()).await);
+	}
+	// a full split is recorded so the next startup can skip the book
+	if let Ok(written) = &result {
+		if let Err(e) = save_manifest(&shared, &task, *written as u64).await {
+			warn!("Manifest of book {} not stored: {:?}", book_id, e);
+		}
+	}
+	if status == JobStatus::Failed {
+		let letter = DeadLetter {
+			book_id,
+			uuid: task.uuid.clone(),
+			path: task.path.to_string_lossy().to_string(),
+			name: task.name.clone(),
+			split: task.split,
+			priority: task.priority,
+			attempts: history,
+			failed_at: now_secs(),
+		};
+		if let Err(e) = dead_letter(&shared, &letter).await {
+			error!("Dead letter of book {} not stored: {:?}", book_id, e);
+		}
+	}
+	let event = JobEvent {
+		book_id,
+		uuid: task.uuid,
+		status,
+		chapters: chapters as u64,
+		changed: changed.into_iter().collect(),
+		duration_ms: started.elapsed().as_millis() as u64,
+		error,
+	};
+	if let Err(e) = publish(&shared, &event).await {
+		warn!("Publish result of book {} err: {:?}", book_id, e);
+	}
+	shared.running.lock().unwrap_or_else(|e| e.into_inner()).remove(&book_id);
+	let _ = done.send(Done { book_id, retry: None });
+	if let Some(reply) = task.done {
+		let _ = reply.send(result);
+	}
+}
+
+// one split of the book under its lock , `Locked` when another owner holds it
+// or takes it over while the split runs
+async fn run_attempt(shared: &Shared, job: &mut Job, mut state: Option<&mut JobStateClient>) -> Result<usize> {
+	let task = &job.task;
+	let book_id = task.book_id;
+	let mut lock = BookLock::new(shared.processor.redis_client.clone(), shared.lock_ttl).await?;
+	let guard = match lock.acquire(book_id, &worker_id()).await? {
+		Some(guard) => guard,
+		None => {
+			let holder = lock.holder(book_id).await?;
+			let expires_in = lock.expires_in(book_id).await?;
+			warn!("Book {} is locked by {:?}", book_id, holder);
+			if let Some(state) = state.as_deref_mut() {
+				log_state(book_id, state.contended(book_id, holder.as_deref()).await);
+			}
+			return Err(Locked { book_id, holder, expires_in }.into());
+		}
+	};
+	if let Some(state) = state.as_deref_mut() {
+		log_state(book_id, state.running(book_id, &worker_id(), guard.token, job.attempt).await);
+	}
+	let mut split = shared.processor.spawn_process_file(task.path.clone(), task.name.clone(), task.split, Some(fence(&lock, &guard)));
+	shared.running.lock().unwrap_or_else(|e| e.into_inner()).insert(book_id, split.canceller());
+	let result = watch_job(&mut split, book_id, state, &mut lock, &guard).await;
+	match lock.release(&guard).await {
+		Ok(true) => {}
+		Ok(false) => warn!("Lock of book {} expired before release", book_id),
+		Err(e) => warn!("Release lock of book {} err: {:?}", book_id, e),
+	}
+	job.record(&split);
+	result
+}
+
+// the split thread blocks on the fence check , it runs on the blocking pool or rayon , never on the runtime
+fn fence(lock: &BookLock, guard: &LockGuard) -> Fence {
+	let (lock, guard) = (lock.clone(), guard.clone());
+	let handle = tokio::runtime::Handle::current();
+	Fence::new(FENCE_CHECK_INTERVAL, move || {
+		let mut lock = lock.clone();
+		match handle.block_on(lock.owns(&guard))? {
+			true => Ok(()),
+			false => Err(Locked {
+				book_id: guard.book_id,
+				holder: handle.block_on(lock.holder(guard.book_id))?,
+				expires_in: handle.block_on(lock.expires_in(guard.book_id))?,
+			}.into()),
+		}
+	})
+}
+
+// only a split of every chapter up to the book's start_count counts as full , a range
+// or a reprocess with another stop would read as a changed book on the next startup
+async fn save_manifest(shared: &Shared, task: &BookTask, chapters: u64) -> Result<()> {
+	let start_count = shared.processor.books.get(task.book_id).await?.and_then(|book| book.start_count);
+	if task.split.from != 0 || start_count != Some(task.split.stop) {
+		return Ok(());
+	}
+	let path = task.path.clone();
+	let ((size, mtime), hash) = tokio::task::spawn_blocking(move || -> Result<_> {
+		Ok((source_stat(&path)?, source_hash(&path)?))
+	}).await??;
+	let manifest = Manifest { size, mtime, hash, stop: task.split.stop, chapters, at: now_secs() };
+	ManifestClient::new(shared.processor.redis_client.clone()).await?.set(task.book_id, &manifest).await
+}
+
+async fn dead_letter(shared: &Shared, letter: &DeadLetter) -> Result<()> {
+	warn!("Book {} failed {} attempts , moved to dead letters", letter.book_id, letter.attempts.len());
+	DeadLetterClient::new(shared.processor.redis_client.clone()).await?.push(letter).await
+}
+
+async fn publish(shared: &Shared, event: &JobEvent) -> Result<()> {
+	EventPublisher::new(shared.processor.redis_client.clone(), shared.events.clone()).await?.publish(event).await
+}
+
+// follows a split until it ends , logging progress and keeping its lock alive
+// a lock lost on renewal cancels the split , the attempt then fails
+async fn watch_job(job: &mut JobHandle, book_id: i32, mut state: Option<&mut JobStateClient>, lock: &mut BookLock, guard: &LockGuard) -> Result<usize> {
+	let mut ticker = interval_at(Instant::now() + PROGRESS_LOG_INTERVAL, PROGRESS_LOG_INTERVAL);
+	let renew_every = lock.ttl() / 3;
+	let mut renewal = interval_at(Instant::now() + renew_every, renew_every);
+	let mut lost = false;
+	let result = loop {
+		tokio::select! {
+			result = job.join() => break result,
+			_ = renewal.tick(), if !lost => {
+				match lock.renew(guard).await {
+					Ok(true) => {}
+					Ok(false) => {
+						error!("Lost lock of book {} , cancel split", book_id);
+						lost = true;
+						job.canceller().cancel();
+					}
+					Err(e) => warn!("Renew lock of book {} err: {:?}", book_id, e),
+				}
+			}
+			_ = ticker.tick() => {
+				let (chapters, bytes) = job.progress();
+				info!("Processing file: {:?} , {} chapters , {} bytes", job.name, chapters, bytes);
+				if let Some(state) = state.as_deref_mut() {
+					log_state(book_id, state.progress(book_id, chapters as u64, bytes).await);
+				}
+			}
+		}
+	};
+	let result = match result {
+		Err(e) if lost && e.is::<Cancelled>() => Err(anyhow!("lost lock of book {}", book_id)),
+		other => other,
+	};
+	match &result {
+		Ok(chapters) => info!("Processed file: {:?} , {} chapters", job.name, chapters),
+		Err(e) if e.is::<Cancelled>() => warn!("Cancelled file: {:?}", job.name),
+		Err(e) if e.is::<Locked>() => warn!("Stopped file: {:?} , {:#}", job.name, e),
+		Err(e) => error!("Error processing file {:?}: {:?}", job.name, e),
+	}
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::CancelPolicy;
+	use crate::processor::processor::tests::{chapters_left, gated_split, processor};
+	use crate::processor::splitter::tests::TempDir;
+
+	fn waiting(seq: u64, book_id: i32, priority: i64) -> Waiting {
+		let task = BookTask {
+			book_id,
+			uuid: format!("uuid-{}", book_id),
+			path: PathBuf::from(format!("{}.txt", book_id)),
+			name: format!("{}.txt", book_id),
+			split: SplitOptions::until(10),
+			priority,
+			done: None,
+		};
+		Waiting { seq, job: Job::new(task), _place: None }
+	}
+
+	// records which tasks ran and whether two runs of a book ever overlapped
+	#[derive(Default)]
+	struct Recorder {
+		active: Mutex<HashSet<i32>>,
+		ran: Mutex<Vec<String>>,
+		overlapped: Mutex<bool>,
+	}
+
+	impl Runner for Arc<Recorder> {
+		fn run(&self, job: Job, done: mpsc::UnboundedSender<Done>) {
+			let recorder = self.clone();
+			tokio::spawn(async move {
+				let book_id = job.task.book_id;
+				if !recorder.active.lock().unwrap().insert(book_id) {
+					*recorder.overlapped.lock().unwrap() = true;
+				}
+				recorder.ran.lock().unwrap().push(job.task.uuid.clone());
+				tokio::time::sleep(Duration::from_millis(50)).await;
+				recorder.active.lock().unwrap().remove(&book_id);
+				let _ = done.send(Done { book_id, retry: None });
+				if let Some(reply) = job.task.done {
+					let _ = reply.send(Ok(1));
+				}
+			});
+		}
+
+		fn withdraw(&self, _book_id: i32) {}
+	}
+
+	fn task(book_id: i32, uuid: &str) -> (BookTask, oneshot::Receiver<Result<usize>>) {
+		let (tx, rx) = oneshot::channel();
+		let mut task = waiting(0, book_id, 0).job.task;
+		task.uuid = uuid.to_string();
+		task.done = Some(tx);
+		(task, rx)
+	}
+
+	#[tokio::test]
+	async fn tasks_of_one_book_never_overlap_and_the_newest_waiting_one_wins() {
+		let recorder = Arc::new(Recorder::default());
+		let (tx, rx) = mpsc::unbounded_channel();
+		let (_cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+		tokio::spawn(dispatch(recorder.clone(), rx, cancel_rx, 4));
+		let (first, first_rx) = task(1, "first");
+		let (second, second_rx) = task(1, "second");
+		let (third, third_rx) = task(1, "third");
+		for task in [first, second, third] {
+			tx.send(Submitted { task, place: None }).unwrap();
+		}
+		assert_eq!(first_rx.await.unwrap().unwrap(), 1);
+		assert!(second_rx.await.is_err(), "the replaced task is dropped without a result");
+		assert_eq!(third_rx.await.unwrap().unwrap(), 1);
+		assert_eq!(*recorder.ran.lock().unwrap(), vec!["first", "third"]);
+		assert!(!*recorder.overlapped.lock().unwrap());
+	}
+
+	#[tokio::test]
+	async fn a_late_high_priority_task_overtakes_every_task_sent_before_it() {
+		let recorder = Arc::new(Recorder::default());
+		let (tx, rx) = mpsc::unbounded_channel();
+		let (_cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+		tokio::spawn(dispatch(recorder.clone(), rx, cancel_rx, 1));
+		let mut results = Vec::new();
+		for (book_id, priority) in [(1, 0), (2, 0), (3, 0), (4, 0), (9, 5)] {
+			let (mut task, rx) = task(book_id, &book_id.to_string());
+			task.priority = priority;
+			tx.send(Submitted { task, place: None }).unwrap();
+			results.push(rx);
+		}
+		for rx in results {
+			rx.await.unwrap().unwrap();
+		}
+		assert_eq!(*recorder.ran.lock().unwrap(), vec!["1", "9", "2", "3", "4"]);
+	}
+
+	#[tokio::test]
+	async fn a_task_gives_its_queue_place_back_when_it_starts() {
+		let recorder = Arc::new(Recorder::default());
+		let (tx, rx) = mpsc::unbounded_channel();
+		let (_cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+		tokio::spawn(dispatch(recorder.clone(), rx, cancel_rx, 1));
+		let places = Arc::new(Semaphore::new(1));
+		let (first, first_rx) = task(1, "first");
+		let (second, _) = task(2, "second");
+		tx.send(Submitted { task: first, place: Some(places.clone().acquire_owned().await.unwrap()) }).unwrap();
+		// the first task runs , its place is free again
+		let place = tokio::time::timeout(Duration::from_secs(1), places.clone().acquire_owned()).await.unwrap().unwrap();
+		tx.send(Submitted { task: second, place: Some(place) }).unwrap();
+		// the second one waits behind it and keeps its place
+		assert!(places.try_acquire().is_err());
+		first_rx.await.unwrap().unwrap();
+	}
+
+	#[tokio::test]
+	async fn cancel_drops_the_waiting_tasks_of_a_book() {
+		let recorder = Arc::new(Recorder::default());
+		let (tx, rx) = mpsc::unbounded_channel();
+		let (cancel_tx, cancel_rx) = mpsc::unbounded_channel();
+		tokio::spawn(dispatch(recorder.clone(), rx, cancel_rx, 4));
+		let (running, running_rx) = task(1, "running");
+		let (deferred, deferred_rx) = task(1, "deferred");
+		let (other, other_rx) = task(2, "other");
+		for task in [running, deferred, other] {
+			tx.send(Submitted { task, place: None }).unwrap();
+		}
+		// the last task started , so every task left the channel
+		while !recorder.ran.lock().unwrap().contains(&"other".to_string()) {
+			tokio::time::sleep(Duration::from_millis(1)).await;
+		}
+		let (reply, dropped) = oneshot::channel();
+		cancel_tx.send((1, reply)).unwrap();
+		assert_eq!(dropped.await.unwrap(), 1);
+		assert!(deferred_rx.await.is_err());
+		assert!(running_rx.await.unwrap().is_ok());
+		assert!(other_rx.await.unwrap().is_ok());
+		assert!(!recorder.ran.lock().unwrap().contains(&"deferred".to_string()));
+	}
+
+	#[tokio::test]
+	async fn a_job_publishes_the_chapters_of_every_attempt() {
+		let tmp = TempDir::new();
+		let processor = processor(&tmp, CancelPolicy::Keep);
+		let mut job = waiting(0, 1, 0).job;
+		// without the test at the gate the first attempt fails before chapter 3
+		let (mut first, _, _) = gated_split(&tmp, &processor);
+		assert!(!first.join().await.unwrap_err().is::<Cancelled>());
+		job.record(&first);
+		let mut second = processor.spawn_process_file(processor.input_dir.join("book.txt"), "book.txt".to_string(), SplitOptions::until(i32::MAX), None);
+		assert_eq!(second.join().await.unwrap(), 6);
+		job.record(&second);
+		assert_eq!(job.changed.into_iter().collect::<Vec<_>>(), vec![0, 2, 3, 4, 5]);
+		assert_eq!(job.chapters, 6);
+	}
+
+	#[tokio::test]
+	async fn cancel_stops_the_running_split_of_a_book() {
+		let tmp = TempDir::new();
+		let processor = processor(&tmp, CancelPolicy::Remove);
+		let config: WatcherConfig = serde_json::from_value(serde_json::json!({ "recursive": false, "concurrent_limit": 1 })).unwrap();
+		let pool = WorkerPool::new(processor.clone(), &config);
+		let (mut job, reached, release) = gated_split(&tmp, &processor);
+		pool.shared.running.lock().unwrap().insert(1, job.canceller());
+		tokio::task::spawn_blocking(move || reached.recv()).await.unwrap().unwrap();
+		assert!(pool.cancel(1).await);
+		assert!(!pool.cancel(2).await, "a book neither running nor waiting");
+		release.send(()).unwrap();
+		assert!(job.join().await.unwrap_err().is::<Cancelled>());
+		assert_eq!(chapters_left(&tmp), ["book1.txt"]);
+	}
+
+	fn drain(mut heap: BinaryHeap<Waiting>) -> Vec<i32> {
+		std::iter::from_fn(|| heap.pop().map(|w| w.job.task.book_id)).collect()
+	}
+
+	#[test]
+	fn higher_priority_runs_first() {
+		let heap = BinaryHeap::from(vec![waiting(0, 1, 10), waiting(1, 2, 1_000_000), waiting(2, 3, -5), waiting(3, 4, 100_000)]);
+		assert_eq!(drain(heap), vec![2, 4, 1, 3]);
+	}
+
+	#[test]
+	fn equal_priority_runs_in_arrival_order() {
+		let heap = BinaryHeap::from(vec![waiting(3, 4, 7), waiting(0, 1, 7), waiting(2, 3, 7), waiting(1, 2, 7)]);
+		assert_eq!(drain(heap), vec![1, 2, 3, 4]);
+	}
+
+	#[test]
+	fn late_high_priority_task_overtakes_waiting_ones() {
+		let mut heap = BinaryHeap::new();
+		for seq in 0..3 {
+			heap.push(waiting(seq, seq as i32 + 1, 0));
+		}
+		heap.push(waiting(3, 9, 1));
+		heap.push(waiting(4, 8, 0));
+		assert_eq!(drain(heap), vec![9, 1, 2, 3, 8]);
+	}
+
+	#[test]
+	fn ordering_is_consistent_with_equality() {
+		assert_eq!(waiting(1, 1, 5), waiting(1, 2, 5));
+		assert_ne!(waiting(1, 1, 5), waiting(2, 1, 5));
+		assert!(waiting(1, 1, 5) > waiting(2, 1, 5));
+		assert!(waiting(9, 1, 6) > waiting(1, 1, 5));
+		assert_eq!(waiting(1, 1, i64::MIN).cmp(&waiting(0, 1, i64::MAX)), Ordering::Less);
+	}
+}
@@ -1,42 +1,83 @@
 use anyhow::{Context, Ok, Result};
-use redis::{cmd, AsyncCommands, Commands};
+use redis::AsyncCommands;
 use std::fs;
-use std::fs::File;
 use std::path::{Path, PathBuf};
-use tokio;
 use walkdir::WalkDir;
-use log::{debug,error,info,warn};
-use std::io::{self,BufRead , BufReader, Read};
-use crate::config::RedisClient;
-use crate::model::book::{PREFIX_QUEUE_BOOK_CDN, BookRedisClient,CHANNEL_PSB_BOOK_TASK};
+use log::{error,info,warn};
+use crate::config::{CancelPolicy, RedisClient};
+use crate::model::book::PREFIX_QUEUE_BOOK_PENDING;
+use crate::model::store::BookStore;
+use super::job::JobHandle;
+use super::splitter::{split_buffered, split_mmap, Cancelled, Fence, Progress, SplitOptions, DEFAULT_LARGE_FILE_THRESHOLD};
 
 use std::sync::Arc;
 
+// name of the output directory of a source file , the name without its extension
+pub fn output_stem(name:&str) -> &str {
+	Path::new(name).file_stem().and_then(|stem| stem.to_str()).unwrap_or(name)
+}
+
+// stem and extension of a source file , chapters are written as `<stem><idx>.<ext>`
+fn split_name(name:&str) -> Result<(&str, &str)> {
+	let path = Path::new(name);
+	match (path.file_stem().and_then(|s| s.to_str()), path.extension().and_then(|e| e.to_str())) {
+		(Some(stem), Some(ext)) if !stem.is_empty() => Result::Ok((stem, ext)),
+		_ => Err(anyhow::anyhow!("source name {:?} has no stem or extension", name)),
+	}
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct FileProcessor {
 	pub input_dir: PathBuf,
 	pub output_dir: PathBuf,
 	pub redis_client: Arc<RedisClient>,
+	// where books are looked up , see `Settings.store`
+	pub books: Arc<dyn BookStore>,
+	pub large_file_threshold: u64,
+	pub cancel_policy: CancelPolicy,
+	pub archive_dir: Option<PathBuf>,
 }
 
 #[allow(dead_code)]
 impl FileProcessor {
-	pub fn new(input_dir: &str, output_dir: &str, redis_client:Arc<RedisClient>) -> Result<Self> {
+	pub fn new(input_dir: &str, output_dir: &str, redis_client:Arc<RedisClient>, books:Arc<dyn BookStore>) -> Result<Self> {
 			fs::create_dir_all(input_dir)?;
-			let mut fp = FileProcessor {
+			let fp = FileProcessor {
 				input_dir: PathBuf::from(input_dir),
 				output_dir: PathBuf::from(output_dir),
-				redis_client: redis_client,
+				redis_client,
+				books,
+				large_file_threshold: DEFAULT_LARGE_FILE_THRESHOLD,
+				cancel_policy: CancelPolicy::default(),
+				archive_dir: None,
 			}	;
 			Ok(fp)
 	}
+
+	// files at least `threshold` bytes are split through the mmap fast path
+	pub fn with_large_file_threshold(mut self, threshold: u64) -> Self {
+			self.large_file_threshold = threshold;
+			self
+	}
+
+	pub fn with_cancel_policy(mut self, policy: CancelPolicy) -> Self {
+			self.cancel_policy = policy;
+			self
+	}
+
+	// orphaned output is moved under `dir` instead of staying in output_dir
+	pub fn with_archive_dir(mut self, dir: Option<PathBuf>) -> Self {
+			self.archive_dir = dir;
+			self
+	}
 	// ? means return error if any error occurs
 	// or return the value ; unpack the value of Result
 	// store file name to redis
 	#[warn(dependency_on_unit_never_type_fallback)]
 	pub async fn store_filenames_to_redis(&self) -> Result<()> {
-			let mut conn = self.redis_client.get_connection().await?;
+			let mut conn = self.redis_client.get_manager().await?;
+			let pending = self.redis_client.keys().key(PREFIX_QUEUE_BOOK_PENDING);
 			info!("redis conn2");
 			for entry in WalkDir::new(&self.input_dir) {
 					let entry = entry?;
@@ -50,7 +91,7 @@ impl FileProcessor {
 									if let Some(filename_str) = filename.to_str() {
 										info!("Filename: {:?}", filename_str);
 											// conn.sadd("files_to_process", filename_str)?;
-											conn.sadd(PREFIX_QUEUE_BOOK_CDN, filename_str).await?;
+											conn.lpush::<_, _, ()>(&pending, filename_str).await?;
 									}
 							}
 					}
@@ -59,12 +100,31 @@ impl FileProcessor {
 			Ok(())
 	}
 
+	// split a file on the blocking pool so the async runtime keeps running
+	// `fence` is asked before chapters are written , see `Fence`
+	pub fn spawn_process_file(self: &Arc<Self>, file_path: PathBuf, name: String, opts: SplitOptions, fence: Option<Fence>) -> JobHandle {
+			let progress = Arc::new(Progress::with_fence(fence));
+			let processor = self.clone();
+			let job_progress = progress.clone();
+			let job_name = name.clone();
+			let task = tokio::task::spawn_blocking(move || {
+				processor.process_file_with_progress(&file_path, &job_name, &opts, &job_progress)
+			});
+			JobHandle::new(name, progress, task)
+	}
+
 	// handle single file
 	pub fn process_file(&self, file_path: &Path, name:&str ,stop: i32 ) -> Result<()> {
+			self.process_file_with_progress(file_path, name, &SplitOptions::until(stop), &Progress::default())?;
+			Ok(())
+	}
+
+	// handle single file , returns the number of chapters written
+	pub fn process_file_with_progress(&self, file_path: &Path, name:&str ,opts: &SplitOptions, progress: &Progress) -> Result<usize> {
 			if !file_path.exists() {
 				// return Err(io::Error::new(io::ErrorKind::NotFound, "File not found").into());
 				error!("File not found : {:?}", &file_path);
-				return Result::Ok(()); 
+				return Result::Ok(0); 
 			}
 			let source_file = if file_path.is_file() {
 				file_path.to_path_buf()
@@ -72,97 +132,222 @@ impl FileProcessor {
 				file_path.join(name)
 			};
 			
-			let file = File::open(&source_file).context(format!("{}\n{}","open file not exit",&source_file.display()))?;
-			let mut reader = BufReader::new(file);
-			const DELIMITER:&str = "###";
-			let mut idx :i32 = 0;
-			let part:Vec<&str> = name.split(".").collect();
-			info!("single 1 part name: {:?} \n ",part);
-			let canon_path= self.output_dir.join(part[0]);
+			let size = fs::metadata(&source_file).context(format!("{}\n{}","open file not exit",&source_file.display()))?.len();
+			let (stem, ext) = split_name(name)?;
+			info!("single 1 part name: {:?} {:?} \n ",stem,ext);
+			let canon_path= self.output_dir.join(stem);
 			if !canon_path.exists(){
 				fs::create_dir_all(&canon_path).context("create output directory  err")?;
 			}
-			let out_path = canon_path.canonicalize().context("ouput dir not eixt")?.to_string_lossy().to_string();
+			let out_path = canon_path.canonicalize().context("ouput dir not eixt")?;
 			info!("single 2 part out_path: {:?} \n ",out_path);
-			let mut content = String::new();
-			let mut line = String::new();
-			while reader.read_line(&mut line)? > 0 {
-					if idx > stop{
-						break;
-					}
-					if line.starts_with(DELIMITER){
-						if content.len() > 0{
-							let full_path = Path::new(&out_path).join(format!("{}{}.{}",part[0],idx,part[1]));
-							if !full_path.exists(){ // if file not exist , create it or do nothing
-								fs::write(full_path, &content)?;
-							}
-							
-							idx += 1;
-							content.clear();
-							
-						}
-						line.clear();
-					}else{
-						content.push_str(&line);
-						line.clear();
-					}
+			let result = if size >= self.large_file_threshold {
+				info!("large file {:?} ({} bytes) , split with mmap",&source_file,size);
+				split_mmap(&source_file, &out_path, stem, ext, opts, progress)
+			} else {
+				split_buffered(&source_file, &out_path, stem, ext, opts, progress)
+			};
+			let written = match result {
+				Err(e) if e.is::<Cancelled>() => {
+					self.cleanup_cancelled(progress);
+					return Err(e);
+				}
+				other => other?,
+			};
+			info!("split {:?} into {} chapters",&source_file,written);
+			Ok(written)
+	}
+
+	// chapter files in the output directory of a source file , 0 when there is none
+	pub fn output_chapters(&self, name:&str) -> usize {
+			let stem = output_stem(name);
+			match fs::read_dir(self.output_dir.join(stem)) {
+				Result::Ok(entries) => entries.filter_map(|e| e.ok()).filter(|e| e.path().is_file()).count(),
+				Err(_) => 0,
 			}
-			Ok(())
 	}
 
-	// handle all files
-	pub async fn process_all_files(&self)->Result<()>{
-			let mut conn = self.redis_client.get_connection().await?;
-			let mut bclient = BookRedisClient::new(self.redis_client.clone()).await?;
-			let files:Vec<String> = conn.smembers(PREFIX_QUEUE_BOOK_CDN).await?;
-			info!("Processing all files {:?}", files);
-			for _file in files{
-					let  _file: &str = &_file;
-					let file = match !_file.contains(".txt") {
-						true => format!("{}.txt",_file),
-						false => _file.to_string(),
-					};
-					let input_path = &self.input_dir;
-					let fpath = input_path.join(&file);
-					info!("Processing all files  file: {:?}", fpath);
-					if fpath.exists(){
-						info!("exist");
-						if let  Some(book) = bclient.get_book_by_source(file.to_string()).await?{
-								match self.process_file(&input_path,&file,book.start_count.unwrap_or(0)){
-										Result::Ok(())=>{
-											//  TODO add map to store the file name
-												// conn.srem("files_to_process", &file)?;
-												cmd("SRM").arg(PREFIX_QUEUE_BOOK_CDN).arg(&file).query_async(&mut conn).await?;
-										
-										}
-										Err(e)=>{
-												error!("Error processing file {:?}: {:?}", input_path, e);
-								}
-							}
-						}
-					}
+	// give the output of a renamed source file the new name , its chapters included ,
+	// false when there is no output
+	pub fn rename_output(&self, old:&str, new:&str) -> Result<bool> {
+			let ((old_stem, old_ext), (new_stem, new_ext)) = (split_name(old)?, split_name(new)?);
+			let (from, to) = (self.output_dir.join(old_stem), self.output_dir.join(new_stem));
+			if !from.is_dir() {
+				return Result::Ok(false);
 			}
-			Ok(())
+			if from != to && to.exists() {
+				return Err(anyhow::anyhow!("output {:?} already exists", to));
+			}
+			let suffix = format!(".{}", old_ext);
+			for entry in fs::read_dir(&from)? {
+				let name = entry?.file_name().to_string_lossy().to_string();
+				let idx = match name.strip_prefix(old_stem).and_then(|rest| rest.strip_suffix(suffix.as_str())) {
+					Some(idx) if !idx.is_empty() && idx.bytes().all(|b| b.is_ascii_digit()) => idx,
+					_ => continue,
+				};
+				fs::rename(from.join(&name), from.join(format!("{}{}.{}", new_stem, idx, new_ext))).context(format!("rename chapter {:?} err", name))?;
+			}
+			fs::rename(&from, &to).context(format!("rename output {:?} err", from))?;
+			info!("renamed output {:?} to {:?}", from, to);
+			Result::Ok(true)
 	}
 
-	pub async fn process_with_retry(&self,input_path: &Path, stop:i32 , max_retries:u32)->Result<()>{
-			let mut retries = 0;
-			loop {
-					let name:&str = input_path.file_name().unwrap().to_str().unwrap_or("");
-					match self.process_file(input_path,name,stop){
-							Result::Ok(_) =>{
-									self.redis_client.get_connection().await?.srem("files_to_process", input_path.file_name().unwrap().to_str().unwrap()).await?;   
-									break;
-							}
-							Err(e)=>{
-									retries += 1;
-									if retries > max_retries{
-											return Err(e);
-									}
-									tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-							}
+	// remove every chapter written for a source file , false when there were none
+	pub fn delete_output(&self, name:&str) -> Result<bool> {
+			let stem = output_stem(name);
+			let dir = self.output_dir.join(stem);
+			if stem.is_empty() || !dir.is_dir() {
+				return Result::Ok(false);
+			}
+			fs::remove_dir_all(&dir).context(format!("remove output {:?} err", dir))?;
+			info!("removed output {:?}", dir);
+			Result::Ok(true)
+	}
+
+	// move the output of a source file to the archive dir as `<stem>-<unix secs>`
+	// None when archiving is off or there is no output
+	pub fn archive_output(&self, name:&str) -> Result<Option<PathBuf>> {
+			let archive_dir = match &self.archive_dir {
+				Some(dir) => dir,
+				None => return Result::Ok(None),
+			};
+			let stem = output_stem(name);
+			let dir = self.output_dir.join(stem);
+			if stem.is_empty() || !dir.is_dir() {
+				return Result::Ok(None);
+			}
+			fs::create_dir_all(archive_dir).context("create archive directory err")?;
+			let secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+			let target = archive_dir.join(format!("{}-{}", stem, secs));
+			fs::rename(&dir, &target).context(format!("archive output {:?} err", dir))?;
+			info!("archived output {:?} to {:?}", dir, target);
+			Result::Ok(Some(target))
+	}
+
+	fn cleanup_cancelled(&self, progress: &Progress) {
+			let written = progress.take_written();
+			warn!("split cancelled after {} new chapters , policy {:?}", written.len(), self.cancel_policy);
+			if self.cancel_policy == CancelPolicy::Remove {
+				for path in written {
+					if let Err(e) = fs::remove_file(&path) {
+						warn!("remove partial chapter {:?} err: {:?}", path, e);
 					}
+				}
 			}
-			Ok(())
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::sync::{mpsc, Mutex};
+	use crate::model::store::MemoryBookStore;
+	use crate::processor::splitter::tests::TempDir;
+
+	pub(crate) fn processor(tmp: &TempDir, policy: CancelPolicy) -> Arc<FileProcessor> {
+		let redis_client = Arc::new(RedisClient::new("redis://127.0.0.1:1", None, "test".to_string()).unwrap());
+		let input = tmp.0.join("input");
+		let output = tmp.0.join("output");
+		let processor = FileProcessor::new(input.to_str().unwrap(), output.to_str().unwrap(), redis_client, Arc::new(MemoryBookStore::new())).unwrap();
+		Arc::new(processor.with_cancel_policy(policy))
+	}
+
+	// a split of a six chapter book , chapter 1 is on disk before it starts
+	// the split waits before writing its third new chapter ( chapter 3 ) until the test
+	// sends on the returned sender , the receiver tells the test it got there
+	pub(crate) fn gated_split(tmp: &TempDir, processor: &Arc<FileProcessor>) -> (JobHandle, mpsc::Receiver<()>, mpsc::Sender<()>) {
+		let source = processor.input_dir.join("book.txt");
+		let book: String = (0..6).map(|i| format!("### {}\nchapter {}\n", i, i)).collect::<String>() + "### end\n";
+		fs::write(&source, book).unwrap();
+		fs::create_dir_all(tmp.0.join("output/book")).unwrap();
+		fs::write(tmp.0.join("output/book/book1.txt"), "kept").unwrap();
+		let (reached_tx, reached_rx) = mpsc::channel();
+		let (release_tx, release_rx) = mpsc::channel();
+		let (reached_tx, release_rx) = (Mutex::new(reached_tx), Mutex::new(release_rx));
+		let calls = AtomicUsize::new(0);
+		let fence = Fence::new(std::time::Duration::ZERO, move || {
+			if calls.fetch_add(1, Ordering::Relaxed) == 2 {
+				reached_tx.lock().unwrap().send(())?;
+				release_rx.lock().unwrap().recv()?;
+			}
+			Result::Ok(())
+		});
+		let job = processor.spawn_process_file(source, "book.txt".to_string(), SplitOptions::until(i32::MAX), Some(fence));
+		(job, reached_rx, release_tx)
+	}
+
+	// chapter files in the output of the gated book , by name
+	pub(crate) fn chapters_left(tmp: &TempDir) -> Vec<String> {
+		let mut names: Vec<String> = fs::read_dir(tmp.0.join("output/book")).unwrap()
+			.map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+			.collect();
+		names.sort();
+		names
+	}
+
+	// cancels the gated split once it reached its third new chapter , which is still written
+	async fn cancel_mid_split(policy: CancelPolicy) -> Vec<String> {
+		let tmp = TempDir::new();
+		let processor = processor(&tmp, policy);
+		let (mut job, reached, release) = gated_split(&tmp, &processor);
+		tokio::task::spawn_blocking(move || reached.recv()).await.unwrap().unwrap();
+		job.canceller().cancel();
+		release.send(()).unwrap();
+		assert!(job.join().await.unwrap_err().is::<Cancelled>());
+		assert_eq!(fs::read_to_string(tmp.0.join("output/book/book1.txt")).unwrap(), "kept");
+		chapters_left(&tmp)
+	}
+
+	#[tokio::test]
+	async fn cancel_keeps_the_chapters_written_so_far() {
+		assert_eq!(cancel_mid_split(CancelPolicy::Keep).await, ["book0.txt", "book1.txt", "book2.txt", "book3.txt"]);
+	}
+
+	#[tokio::test]
+	async fn cancel_removes_only_the_chapters_this_split_wrote() {
+		assert_eq!(cancel_mid_split(CancelPolicy::Remove).await, ["book1.txt"]);
+	}
+
+	#[test]
+	fn renamed_source_takes_its_output_along() {
+		let tmp = TempDir::new();
+		let processor = processor(&tmp, CancelPolicy::Keep);
+		let old = tmp.0.join("output/book");
+		fs::create_dir_all(&old).unwrap();
+		for name in ["book0.txt", "book1.txt", "notes.md"] {
+			fs::write(old.join(name), name).unwrap();
+		}
+		assert!(processor.rename_output("book.txt", "novel.md").unwrap());
+		assert!(!old.exists());
+		let mut names: Vec<String> = fs::read_dir(tmp.0.join("output/novel")).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+		names.sort();
+		assert_eq!(names, ["notes.md", "novel0.md", "novel1.md"]);
+		assert_eq!(fs::read_to_string(tmp.0.join("output/novel/novel1.md")).unwrap(), "book1.txt");
+		assert!(!processor.rename_output("book.txt", "other.txt").unwrap(), "no output left to rename");
+	}
+
+	#[test]
+	fn renamed_output_does_not_replace_another_one() {
+		let tmp = TempDir::new();
+		let processor = processor(&tmp, CancelPolicy::Keep);
+		fs::create_dir_all(tmp.0.join("output/book")).unwrap();
+		fs::create_dir_all(tmp.0.join("output/novel")).unwrap();
+		assert!(processor.rename_output("book.txt", "novel.txt").is_err());
+		assert!(tmp.0.join("output/book").is_dir());
+	}
+
+	#[test]
+	fn source_names_split_at_the_last_dot() {
+		assert_eq!(split_name("book.txt").unwrap(), ("book", "txt"));
+		assert_eq!(split_name("vol.1.txt").unwrap(), ("vol.1", "txt"));
+		assert_eq!(output_stem("vol.1.txt"), "vol.1");
+	}
+
+	#[test]
+	fn source_names_without_extension_are_rejected() {
+		assert!(split_name("book").is_err());
+		assert!(split_name(".txt").is_err());
+		assert_eq!(output_stem("book"), "book");
 	}
 }
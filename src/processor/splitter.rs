@@ -0,0 +1,360 @@
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// every chapter starts with a line beginning with this marker
+pub const DELIMITER: &str = "###";
+// files at least this big take the mmap path
+pub const DEFAULT_LARGE_FILE_THRESHOLD: u64 = 64 * 1024 * 1024;
+// smallest chunk handed to one rayon worker when scanning for headings
+const SCAN_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// asked before a chapter file is written , at most once per `every` , an error stops the split
+// a split that stalled longer than `every` asks again before its next write
+pub struct Fence {
+	check: Box<dyn Fn() -> Result<()> + Send + Sync>,
+	every: Duration,
+	last: Mutex<Option<Instant>>,
+}
+
+impl Fence {
+	pub fn new(every: Duration, check: impl Fn() -> Result<()> + Send + Sync + 'static) -> Self {
+		Self { check: Box::new(check), every, last: Mutex::new(None) }
+	}
+
+	// concurrent writers wait for the check in progress instead of asking again
+	fn check(&self) -> Result<()> {
+		let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+		if last.is_some_and(|at| at.elapsed() < self.every) {
+			return Ok(());
+		}
+		(self.check)()?;
+		*last = Some(Instant::now());
+		Ok(())
+	}
+}
+
+impl std::fmt::Debug for Fence {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "Fence {{ every: {:?} }}", self.every)
+	}
+}
+
+// shared state of a running split , counters are safe to read from another thread
+// and `cancel` stops the split before its next chapter
+#[derive(Debug, Default)]
+pub struct Progress {
+	pub chapters: AtomicUsize,
+	pub bytes: AtomicU64,
+	cancelled: AtomicBool,
+	// chapter files created by this run with their index , existing ones are not listed
+	written: Mutex<Vec<(usize, PathBuf)>>,
+	fence: Option<Fence>,
+}
+
+impl Progress {
+	pub fn with_fence(fence: Option<Fence>) -> Self {
+		Self { fence, ..Self::default() }
+	}
+
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::Relaxed)
+	}
+
+	pub fn take_written(&self) -> Vec<PathBuf> {
+		let written = std::mem::take(&mut *self.written.lock().unwrap_or_else(|e| e.into_inner()));
+		written.into_iter().map(|(_, path)| path).collect()
+	}
+
+	// indexes of the chapters created by this run , ascending
+	pub fn written_chapters(&self) -> Vec<usize> {
+		let mut idx: Vec<usize> = self.written.lock().unwrap_or_else(|e| e.into_inner()).iter().map(|(idx, _)| *idx).collect();
+		idx.sort_unstable();
+		idx
+	}
+
+	fn chapter_done(&self, len: usize) {
+		self.chapters.fetch_add(1, Ordering::Relaxed);
+		self.bytes.fetch_add(len as u64, Ordering::Relaxed);
+	}
+}
+
+// which chapters a split writes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitOptions {
+	// chapters before this index are read but not written
+	#[serde(default)]
+	pub from: usize,
+	// index of the last chapter written , negative writes none
+	pub stop: i32,
+	// replace chapter files that already exist instead of keeping them
+	#[serde(default)]
+	pub overwrite: bool,
+}
+
+impl SplitOptions {
+	// chapters `0..=stop` , existing ones are kept
+	pub fn until(stop: i32) -> Self {
+		Self { from: 0, stop, overwrite: false }
+	}
+}
+
+// returned when a split stopped because its job was cancelled
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "split cancelled")
+	}
+}
+
+impl std::error::Error for Cancelled {}
+
+// write one chapter unless it already exists or is before `from` , checks the cancel flag first
+// and the fence before the file is written
+fn write_chapter(out_dir: &Path, stem: &str, idx: usize, ext: &str, content: &[u8], opts: &SplitOptions, progress: &Progress) -> Result<()> {
+	if progress.is_cancelled() {
+		return Err(Cancelled.into());
+	}
+	if idx < opts.from {
+		return Ok(());
+	}
+	let path = out_dir.join(format!("{}{}.{}", stem, idx, ext));
+	if opts.overwrite || !path.exists() { // if file not exist , create it or do nothing
+		if let Some(fence) = progress.fence.as_ref() {
+			fence.check()?;
+		}
+		fs::write(&path, content)?;
+		progress.written.lock().unwrap_or_else(|e| e.into_inner()).push((idx, path));
+	}
+	progress.chapter_done(content.len());
+	Ok(())
+}
+
+// line by line split , keeps one chapter in memory at a time
+// chapters are written as `{stem}{idx}.{ext}` , those in `opts.from..=opts.stop`
+// content after the last heading is not written , returns the chapters read
+pub fn split_buffered(source: &Path, out_dir: &Path, stem: &str, ext: &str, opts: &SplitOptions, progress: &Progress) -> Result<usize> {
+	let file = File::open(source).context(format!("{}\n{}", "open file not exit", source.display()))?;
+	let mut reader = BufReader::new(file);
+	let mut idx: i32 = 0;
+	let mut content = String::new();
+	let mut line = String::new();
+	while reader.read_line(&mut line)? > 0 {
+		if idx > opts.stop {
+			break;
+		}
+		if line.starts_with(DELIMITER) {
+			if !content.is_empty() {
+				write_chapter(out_dir, stem, idx as usize, ext, content.as_bytes(), opts, progress)?;
+				idx += 1;
+				content.clear();
+			}
+		} else {
+			content.push_str(&line);
+		}
+		line.clear();
+	}
+	Ok(idx as usize)
+}
+
+// memory mapped split for large files
+// headings are located in parallel chunks and chapters are written concurrently ,
+// output is identical to `split_buffered`
+pub fn split_mmap(source: &Path, out_dir: &Path, stem: &str, ext: &str, opts: &SplitOptions, progress: &Progress) -> Result<usize> {
+	let file = File::open(source).context(format!("{}\n{}", "open file not exit", source.display()))?;
+	if file.metadata()?.len() == 0 {
+		return Ok(0);
+	}
+	// SAFETY: source files are only read here , a concurrent truncate is an operator error
+	let data = unsafe { Mmap::map(&file) }.context("mmap source file err")?;
+	let chapters = chapter_ranges(&data, opts.stop);
+	chapters
+		.par_iter()
+		.enumerate()
+		.try_for_each(|(idx, range)| -> Result<()> {
+			write_chapter(out_dir, stem, idx, ext, &data[range.clone()], opts, progress)
+		})?;
+	Ok(chapters.len())
+}
+
+// byte offsets of every line starting with the delimiter , found in parallel
+pub fn find_headings(data: &[u8]) -> Vec<usize> {
+	let chunks = (data.len() / SCAN_CHUNK_SIZE).max(1);
+	let chunk_len = data.len().div_ceil(chunks);
+	(0..chunks)
+		.into_par_iter()
+		.flat_map_iter(|c| {
+			let start = c * chunk_len;
+			let end = ((c + 1) * chunk_len).min(data.len());
+			(start..end).filter(move |&p| is_heading(data, p))
+		})
+		.collect()
+}
+
+fn is_heading(data: &[u8], pos: usize) -> bool {
+	(pos == 0 || data[pos - 1] == b'\n') && data[pos..].starts_with(DELIMITER.as_bytes())
+}
+
+// non empty chapter bodies in order , limited to `stop + 1` entries
+fn chapter_ranges(data: &[u8], stop: i32) -> Vec<std::ops::Range<usize>> {
+	let limit = (stop as i64 + 1).max(0) as usize;
+	let mut ranges = Vec::new();
+	let mut body_start = 0;
+	for heading in find_headings(data) {
+		if ranges.len() >= limit {
+			break;
+		}
+		if heading > body_start {
+			ranges.push(body_start..heading);
+		}
+		body_start = match data[heading..].iter().position(|&b| b == b'\n') {
+			Some(n) => heading + n + 1,
+			None => data.len(),
+		};
+	}
+	ranges
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use std::collections::BTreeMap;
+	use std::sync::atomic::AtomicUsize;
+
+	static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+	// a fresh directory under the system temp dir , removed on drop
+	pub(crate) struct TempDir(pub(crate) PathBuf);
+
+	impl TempDir {
+		pub(crate) fn new() -> Self {
+			let n = DIRS.fetch_add(1, Ordering::Relaxed);
+			let dir = std::env::temp_dir().join(format!("xreader_splitter_{}_{}", std::process::id(), n));
+			let _ = fs::remove_dir_all(&dir);
+			fs::create_dir_all(&dir).unwrap();
+			Self(dir)
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	type Split = fn(&Path, &Path, &str, &str, &SplitOptions, &Progress) -> Result<usize>;
+
+	// chapters read and the files written , by name
+	fn run(split: Split, source: &Path, dir: &Path, opts: &SplitOptions) -> (usize, BTreeMap<String, Vec<u8>>) {
+		fs::create_dir_all(dir).unwrap();
+		let read = split(source, dir, "book", "txt", opts, &Progress::default()).unwrap();
+		let files = fs::read_dir(dir).unwrap()
+			.map(|e| e.unwrap())
+			.map(|e| (e.file_name().to_string_lossy().to_string(), fs::read(e.path()).unwrap()))
+			.collect();
+		(read, files)
+	}
+
+	// both paths agree , the buffered result is returned
+	fn split_both(content: &str, opts: SplitOptions) -> (usize, BTreeMap<String, Vec<u8>>) {
+		let tmp = TempDir::new();
+		let source = tmp.0.join("book.txt");
+		fs::write(&source, content).unwrap();
+		let buffered = run(split_buffered, &source, &tmp.0.join("buffered"), &opts);
+		let mmap = run(split_mmap, &source, &tmp.0.join("mmap"), &opts);
+		assert_eq!(buffered, mmap, "split_buffered and split_mmap differ on {:?}", content);
+		buffered
+	}
+
+	fn chapter(files: &BTreeMap<String, Vec<u8>>, idx: usize) -> &str {
+		std::str::from_utf8(&files[&format!("book{}.txt", idx)]).unwrap()
+	}
+
+	#[test]
+	fn content_after_the_last_heading_is_not_written() {
+		let (read, files) = split_both("### 1\none\n### 2\ntwo\n", SplitOptions::until(i32::MAX));
+		assert_eq!(read, 1);
+		assert_eq!(files.len(), 1);
+		assert_eq!(chapter(&files, 0), "one\n");
+	}
+
+	#[test]
+	fn text_before_the_first_heading_is_a_chapter() {
+		let (read, files) = split_both("intro\n### 1\none\n### end\n", SplitOptions::until(i32::MAX));
+		assert_eq!(read, 2);
+		assert_eq!(chapter(&files, 0), "intro\n");
+		assert_eq!(chapter(&files, 1), "one\n");
+	}
+
+	#[test]
+	fn crlf_line_endings_are_kept() {
+		let (read, files) = split_both("### 1\r\none\r\n### 2\r\ntwo\r\n### end\r\n", SplitOptions::until(i32::MAX));
+		assert_eq!(read, 2);
+		assert_eq!(chapter(&files, 0), "one\r\n");
+		assert_eq!(chapter(&files, 1), "two\r\n");
+	}
+
+	#[test]
+	fn heading_at_eof_without_newline_ends_the_last_chapter() {
+		let (read, files) = split_both("### 1\none\n### 2\ntwo\n### end", SplitOptions::until(i32::MAX));
+		assert_eq!(read, 2);
+		assert_eq!(chapter(&files, 1), "two\n");
+	}
+
+	#[test]
+	fn delimiter_inside_a_line_is_no_heading() {
+		let (read, files) = split_both("### 1\none ### not\n### end\n", SplitOptions::until(i32::MAX));
+		assert_eq!(read, 1);
+		assert_eq!(chapter(&files, 0), "one ### not\n");
+	}
+
+	#[test]
+	fn headings_are_found_across_scan_chunks() {
+		let body = "a line of chapter text\n".repeat(1000);
+		let book: String = (0..400).map(|i| format!("### {}\n{}", i, body)).collect::<String>() + "### end\n";
+		assert!(book.len() > 2 * SCAN_CHUNK_SIZE);
+		let (read, files) = split_both(&book, SplitOptions::until(i32::MAX));
+		assert_eq!(read, 400);
+		assert_eq!(files.len(), 400);
+	}
+
+	#[test]
+	fn empty_file_writes_nothing() {
+		let (read, files) = split_both("", SplitOptions::until(i32::MAX));
+		assert_eq!(read, 0);
+		assert!(files.is_empty());
+	}
+
+	#[test]
+	fn stop_limits_the_chapters_written() {
+		let book = "### 1\none\n### 2\ntwo\n### 3\nthree\n### end\n";
+		let (read, files) = split_both(book, SplitOptions::until(1));
+		assert_eq!(read, 2);
+		assert_eq!(files.keys().collect::<Vec<_>>(), ["book0.txt", "book1.txt"]);
+		let (read, files) = split_both(book, SplitOptions::until(-1));
+		assert_eq!(read, 0);
+		assert!(files.is_empty());
+	}
+
+	#[test]
+	fn chapters_before_from_are_read_but_not_written() {
+		let book = "### 1\none\n### 2\ntwo\n### 3\nthree\n### end\n";
+		let (read, files) = split_both(book, SplitOptions { from: 1, stop: 1, overwrite: false });
+		assert_eq!(read, 2);
+		assert_eq!(files.keys().collect::<Vec<_>>(), ["book1.txt"]);
+		assert_eq!(chapter(&files, 1), "two\n");
+	}
+}
@@ -1,2 +1,6 @@
+#[allow(clippy::module_inception)]
 mod watcher;
-pub use watcher::FileWatcher;
\ No newline at end of file
+mod reconcile;
+mod rename;
+mod upload;
+pub use watcher::FileWatcher;
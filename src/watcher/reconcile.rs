@@ -0,0 +1,198 @@
+use anyhow::Result;
+use log::{info, warn};
+use redis::cmd;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
+
+use crate::model::book::PREFIX_QUEUE_BOOK_RECONCILE;
+use crate::model::lock::BookLock;
+use crate::model::state::{now_secs, JobStateClient, JobStatus};
+use crate::processor::splitter::SplitOptions;
+use crate::processor::{output_stem, BookTask, FileProcessor, WorkerPool};
+
+// what one reconciliation pass found and did
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileSummary {
+	pub at: i64,
+	pub duration_ms: u64,
+	// books in redis , files in input_dir , directories in output_dir
+	pub books: usize,
+	pub sources: usize,
+	pub outputs: usize,
+	// source files not seen by the previous pass
+	pub new_sources: usize,
+	// books enqueued because their output is missing , has fewer chapters than the
+	// last finished job wrote , or is older than the source
+	pub missing: usize,
+	pub incomplete: usize,
+	pub stale: usize,
+	pub enqueued: usize,
+	// books queued or running , or whose source is still being uploaded , left alone
+	pub in_flight: usize,
+	// books left running by a worker that no longer holds their lock , enqueued again
+	pub abandoned: usize,
+	// books whose last job failed or was cancelled , left alone until the source changes
+	pub settled: usize,
+	// books whose source file is not in input_dir
+	pub source_missing: usize,
+	// files in input_dir and directories in output_dir no book points at
+	pub unmatched_sources: usize,
+	pub orphan_outputs: usize,
+}
+
+// chapter files of an output directory and when the newest one was written
+struct Output {
+	chapters: usize,
+	modified: Option<SystemTime>,
+}
+
+fn read_output(dir: &Path) -> Result<Output> {
+	let mut output = Output { chapters: 0, modified: None };
+	for entry in fs::read_dir(dir)? {
+		let meta = entry?.metadata()?;
+		if !meta.is_file() {
+			continue;
+		}
+		output.chapters += 1;
+		let modified = meta.modified().ok();
+		if modified > output.modified {
+			output.modified = modified;
+		}
+	}
+	Ok(output)
+}
+
+// 0 when the time is unknown
+fn unix_secs(time: Option<SystemTime>) -> i64 {
+	time.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok()).map(|d| d.as_secs() as i64).unwrap_or(0)
+}
+
+// top level entries of a directory by name , files or directories
+fn list_dir(dir: &Path, dirs: bool) -> Result<HashMap<String, PathBuf>> {
+	let mut found = HashMap::new();
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		if entry.file_type()?.is_dir() == dirs {
+			found.insert(entry.file_name().to_string_lossy().to_string(), entry.path());
+		}
+	}
+	Ok(found)
+}
+
+// compare the books in redis with input_dir and output_dir and enqueue every book whose
+// output is missing , incomplete or stale , or that a dead worker left running ,
+// `known_file` holds the sources seen so far
+// and `uploading` the sources still being uploaded
+pub async fn reconcile(processor: &FileProcessor, pool: &WorkerPool, known_file: &mut HashSet<PathBuf>, uploading: &HashSet<PathBuf>) -> Result<ReconcileSummary> {
+	let started = Instant::now();
+	let mut summary = ReconcileSummary { at: now_secs(), ..Default::default() };
+	let sources = list_dir(&processor.input_dir, false)?;
+	let outputs = if processor.output_dir.is_dir() { list_dir(&processor.output_dir, true)? } else { HashMap::new() };
+	summary.sources = sources.len();
+	summary.outputs = outputs.len();
+	summary.new_sources = sources.values().filter(|path| !known_file.contains(*path)).count();
+	*known_file = sources.values().cloned().collect();
+
+	let mut state = JobStateClient::new(processor.redis_client.clone()).await?;
+	let mut lock = BookLock::new(processor.redis_client.clone(), pool.lock_ttl()).await?;
+	let mut matched_sources = HashSet::new();
+	let mut matched_outputs = HashSet::new();
+	for book_id in processor.books.list().await? {
+		let book = match processor.books.get(book_id).await? {
+			Some(book) => book,
+			None => continue,
+		};
+		summary.books += 1;
+		let file = match book.source_name() {
+			Some(file) => file,
+			None => continue,
+		};
+		let stem = output_stem(&file).to_string();
+		matched_outputs.insert(stem.clone());
+		let source = match sources.get(&file) {
+			Some(source) => source,
+			None => {
+				summary.source_missing += 1;
+				continue;
+			}
+		};
+		matched_sources.insert(file.clone());
+		// books without start_count are never split
+		let stop = match book.start_count {
+			Some(stop) => stop,
+			None => continue,
+		};
+		if uploading.contains(source) {
+			summary.in_flight += 1;
+			continue;
+		}
+		let source_modified = fs::metadata(source)?.modified().ok();
+		let job = state.get(book_id).await?;
+		let abandoned = match job.as_ref().map(|job| job.status) {
+			Some(JobStatus::Queued) => {
+				summary.in_flight += 1;
+				continue;
+			}
+			// a worker that died mid split left the book running without a lock holder
+			Some(JobStatus::Running) if lock.holder(book_id).await?.is_some() => {
+				summary.in_flight += 1;
+				continue;
+			}
+			Some(JobStatus::Running) => true,
+			_ => false,
+		};
+		// dead lettered or cancelled on purpose , a newer source is split again
+		if job.as_ref().is_some_and(|job| matches!(job.status, JobStatus::Failed | JobStatus::Cancelled) && unix_secs(source_modified) <= job.ended_at) {
+			summary.settled += 1;
+			continue;
+		}
+		let mut split = SplitOptions::until(stop);
+		match outputs.get(&stem).map(|dir| read_output(dir)).transpose()? {
+			// whatever it wrote is kept , the split fills in the rest
+			_ if abandoned => summary.abandoned += 1,
+			None | Some(Output { chapters: 0, .. }) => summary.missing += 1,
+			Some(output) => {
+				let expected = job.filter(|job| job.status == JobStatus::Done).map(|job| job.chapters as usize).unwrap_or(0);
+				if source_modified > output.modified {
+					// the source changed after the split , every chapter is rewritten
+					summary.stale += 1;
+					split.overwrite = true;
+				} else if output.chapters < expected {
+					summary.incomplete += 1;
+				} else {
+					continue;
+				}
+			}
+		}
+		pool.submit(BookTask {
+			book_id,
+			uuid: book.uuid.clone(),
+			path: source.clone(),
+			name: file,
+			split,
+			priority: book.priority(),
+			done: None,
+		}).await?;
+		summary.enqueued += 1;
+	}
+	summary.unmatched_sources = sources.keys().filter(|name| !matched_sources.contains(*name)).count();
+	summary.orphan_outputs = outputs.keys().filter(|name| !matched_outputs.contains(*name)).count();
+	summary.duration_ms = started.elapsed().as_millis() as u64;
+	if let Err(e) = store_summary(processor, &summary).await {
+		warn!("Store reconcile summary err: {:?}", e);
+	}
+	info!("Reconciled: {:?}", summary);
+	Ok(summary)
+}
+
+async fn store_summary(processor: &FileProcessor, summary: &ReconcileSummary) -> Result<()> {
+	let mut conn = processor.redis_client.get_manager().await?;
+	cmd("SET")
+		.arg(processor.redis_client.keys().key(PREFIX_QUEUE_BOOK_RECONCILE))
+		.arg(serde_json::to_string(summary)?)
+		.query_async::<_, ()>(&mut conn).await?;
+	Ok(())
+}
@@ -0,0 +1,115 @@
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// how long the old path of a rename waits for its new one
+pub const RENAME_WAIT: Duration = Duration::from_secs(1);
+
+// the old path of a rename , waiting for its other half
+#[derive(Debug)]
+struct Held {
+	tracker: Option<usize>,
+	path: PathBuf,
+	at: Instant,
+}
+
+// pairs the halves of a rename , inotify reports `From` with the old path before
+// `To` and `Both` with the new one , all under one tracker cookie
+// a `From` no `Both` follows within `wait` was moved out of input_dir , it counts as removed
+#[derive(Debug)]
+pub struct RenameTracker {
+	wait: Duration,
+	held: Vec<Held>,
+}
+
+impl RenameTracker {
+	pub fn new(wait: Duration) -> Self {
+		Self { wait, held: Vec::new() }
+	}
+
+	// how often `expired` should run
+	pub fn poll_interval(&self) -> Duration {
+		self.wait.max(Duration::from_millis(100))
+	}
+
+	// (from , to) of a finished rename , a `From` is held until then
+	pub fn on_event(&mut self, event: &Event) -> Option<(PathBuf, PathBuf)> {
+		match event.kind {
+			EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+				let tracker = event.attrs.tracker();
+				self.held.extend(event.paths.iter().map(|path| Held { tracker, path: path.clone(), at: Instant::now() }));
+				None
+			}
+			EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+				let (from, to) = (&event.paths[0], &event.paths[1]);
+				let tracker = event.attrs.tracker();
+				self.held.retain(|held| !(tracker.is_some() && held.tracker == tracker || &held.path == from));
+				Some((from.clone(), to.clone()))
+			}
+			_ => None,
+		}
+	}
+
+	// old paths whose rename never finished , they were moved away
+	pub fn expired(&mut self) -> Vec<PathBuf> {
+		let (gone, held): (Vec<_>, Vec<_>) = self.held.drain(..).partition(|held| held.at.elapsed() >= self.wait);
+		self.held = held;
+		gone.into_iter().map(|held| held.path).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn event(mode: RenameMode, tracker: Option<usize>, paths: &[&str]) -> Event {
+		let event = paths.iter().fold(Event::new(EventKind::Modify(ModifyKind::Name(mode))), |event, path| event.add_path(PathBuf::from(path)));
+		match tracker {
+			Some(tracker) => event.set_tracker(tracker),
+			None => event,
+		}
+	}
+
+	#[test]
+	fn from_without_its_other_half_is_a_removal() {
+		let mut renames = RenameTracker::new(Duration::ZERO);
+		assert_eq!(renames.on_event(&event(RenameMode::From, Some(7), &["in/a.txt"])), None);
+		assert_eq!(renames.expired(), vec![PathBuf::from("in/a.txt")]);
+		assert!(renames.expired().is_empty());
+	}
+
+	#[test]
+	fn from_is_held_while_it_waits() {
+		let mut renames = RenameTracker::new(Duration::from_secs(60));
+		renames.on_event(&event(RenameMode::From, Some(7), &["in/a.txt"]));
+		assert!(renames.expired().is_empty());
+	}
+
+	#[test]
+	fn from_to_and_both_of_one_tracker_are_a_rename() {
+		let mut renames = RenameTracker::new(Duration::ZERO);
+		assert_eq!(renames.on_event(&event(RenameMode::From, Some(7), &["in/a.txt"])), None);
+		assert_eq!(renames.on_event(&event(RenameMode::To, Some(7), &["in/b.txt"])), None);
+		let renamed = renames.on_event(&event(RenameMode::Both, Some(7), &["in/a.txt", "in/b.txt"]));
+		assert_eq!(renamed, Some((PathBuf::from("in/a.txt"), PathBuf::from("in/b.txt"))));
+		assert!(renames.expired().is_empty());
+	}
+
+	#[test]
+	fn both_only_pairs_its_own_from() {
+		let mut renames = RenameTracker::new(Duration::ZERO);
+		renames.on_event(&event(RenameMode::From, Some(7), &["in/a.txt"]));
+		renames.on_event(&event(RenameMode::From, Some(8), &["in/c.txt"]));
+		renames.on_event(&event(RenameMode::Both, Some(7), &["in/a.txt", "in/b.txt"]));
+		assert_eq!(renames.expired(), vec![PathBuf::from("in/c.txt")]);
+	}
+
+	#[test]
+	fn from_without_tracker_pairs_by_path() {
+		let mut renames = RenameTracker::new(Duration::ZERO);
+		renames.on_event(&event(RenameMode::From, None, &["in/a.txt"]));
+		assert!(renames.on_event(&event(RenameMode::Both, None, &["in/a.txt", "in/b.txt"])).is_some());
+		assert!(renames.expired().is_empty());
+	}
+}
@@ -0,0 +1,266 @@
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::config::UploadConfig;
+
+// last seen size and mtime of a file still being written
+#[derive(Debug)]
+struct Pending {
+	size: u64,
+	modified: Option<SystemTime>,
+	stable_since: Instant,
+}
+
+// holds back files written into input_dir until their upload is complete ,
+// see `UploadConfig` for what counts as complete
+#[derive(Debug)]
+pub struct UploadTracker {
+	config: UploadConfig,
+	pending: HashMap<PathBuf, Pending>,
+}
+
+fn stat(path: &Path) -> Option<(u64, Option<SystemTime>)> {
+	let meta = fs::metadata(path).ok().filter(|m| m.is_file())?;
+	Some((meta.len(), meta.modified().ok()))
+}
+
+fn has_suffix(path: &Path, suffix: &str) -> bool {
+	!suffix.is_empty() && path.to_string_lossy().ends_with(suffix)
+}
+
+impl UploadTracker {
+	pub fn new(config: UploadConfig) -> Self {
+		Self { config, pending: HashMap::new() }
+	}
+
+	pub fn quiet_period(&self) -> Duration {
+		Duration::from_secs(self.config.quiet_period)
+	}
+
+	// how often `poll` should run to notice quiet files in time
+	pub fn poll_interval(&self) -> Duration {
+		(self.quiet_period() / 4).max(Duration::from_secs(1))
+	}
+
+	// files still being written , a copy for a pass running elsewhere
+	pub fn pending_paths(&self) -> HashSet<PathBuf> {
+		self.pending.keys().cloned().collect()
+	}
+
+	// the file is gone or was moved away
+	pub fn forget(&mut self, path: &Path) {
+		self.pending.remove(path);
+	}
+
+	// is the file an upload marker or still carries the partial suffix
+	pub fn is_marker(&self, path: &Path) -> bool {
+		has_suffix(path, &self.config.done_suffix) || has_suffix(path, &self.config.part_suffix)
+	}
+
+	// files the event shows complete right away , other written files start waiting
+	pub fn on_event(&mut self, event: &Event) -> Vec<PathBuf> {
+		let mut ready = Vec::new();
+		match event.kind {
+			EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+				let (from, to) = (&event.paths[0], &event.paths[1]);
+				self.pending.remove(from);
+				if has_suffix(from, &self.config.part_suffix) && !self.is_marker(to) && to.is_file() {
+					self.pending.remove(to);
+					ready.push(to.clone());
+				} else {
+					self.track(to);
+				}
+			}
+			EventKind::Create(_) | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Any | ModifyKind::Name(RenameMode::To)) => {
+				for path in event.paths.iter() {
+					if has_suffix(path, &self.config.done_suffix) {
+						let target = PathBuf::from(path.to_string_lossy().trim_end_matches(self.config.done_suffix.as_str()));
+						if target.is_file() {
+							self.pending.remove(&target);
+							ready.push(target);
+						}
+					} else if !self.is_marker(path) {
+						self.track(path);
+					}
+				}
+			}
+			_ => {}
+		}
+		ready
+	}
+
+	// files whose size and mtime did not change for the quiet period
+	pub fn poll(&mut self) -> Vec<PathBuf> {
+		let quiet = self.quiet_period();
+		let mut ready = Vec::new();
+		self.pending.retain(|path, pending| {
+			let (size, modified) = match stat(path) {
+				Some(found) => found,
+				None => return false,
+			};
+			if size != pending.size || modified != pending.modified {
+				*pending = Pending { size, modified, stable_since: Instant::now() };
+				return true;
+			}
+			if pending.stable_since.elapsed() >= quiet {
+				ready.push(path.clone());
+				return false;
+			}
+			true
+		});
+		ready
+	}
+
+	// start or restart the quiet period of a written file
+	fn track(&mut self, path: &Path) {
+		if let Some((size, modified)) = stat(path) {
+			self.pending.insert(path.to_path_buf(), Pending { size, modified, stable_since: Instant::now() });
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::processor::splitter::tests::TempDir;
+	use notify::event::{CreateKind, DataChange};
+
+	fn tracker(quiet_period: u64) -> UploadTracker {
+		UploadTracker::new(UploadConfig { quiet_period, ..UploadConfig::default() })
+	}
+
+	fn event(kind: EventKind, paths: &[&PathBuf]) -> Event {
+		paths.iter().fold(Event::new(kind), |event, path| event.add_path(path.to_path_buf()))
+	}
+
+	fn created(path: &PathBuf) -> Event {
+		event(EventKind::Create(CreateKind::File), &[path])
+	}
+
+	fn renamed(from: &PathBuf, to: &PathBuf) -> Event {
+		event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &[from, to])
+	}
+
+	#[test]
+	fn markers_are_recognised_by_suffix() {
+		let tracker = tracker(10);
+		assert!(tracker.is_marker(Path::new("in/a.txt.done")));
+		assert!(tracker.is_marker(Path::new("in/a.txt.part")));
+		assert!(!tracker.is_marker(Path::new("in/a.txt")));
+		assert!(!tracker.is_marker(Path::new("in/a.done.txt")));
+		let no_suffixes = UploadTracker::new(UploadConfig { quiet_period: 10, done_suffix: String::new(), part_suffix: String::new() });
+		assert!(!no_suffixes.is_marker(Path::new("in/a.txt")));
+	}
+
+	#[test]
+	fn written_file_is_ready_after_the_quiet_period() {
+		let tmp = TempDir::new();
+		let path = tmp.0.join("a.txt");
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(0);
+		assert!(tracker.on_event(&created(&path)).is_empty());
+		assert_eq!(tracker.pending_paths(), HashSet::from([path.clone()]));
+		assert_eq!(tracker.poll(), vec![path]);
+		assert!(tracker.pending_paths().is_empty());
+		assert!(tracker.poll().is_empty());
+	}
+
+	#[test]
+	fn file_is_held_back_while_the_quiet_period_runs() {
+		let tmp = TempDir::new();
+		let path = tmp.0.join("a.txt");
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(60);
+		tracker.on_event(&created(&path));
+		assert!(tracker.poll().is_empty());
+		assert!(tracker.pending_paths().contains(&path));
+	}
+
+	#[test]
+	fn growing_file_restarts_its_quiet_period() {
+		let tmp = TempDir::new();
+		let path = tmp.0.join("a.txt");
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(0);
+		tracker.on_event(&created(&path));
+		fs::write(&path, "one two").unwrap();
+		// the size changed since the event , this poll only notes it
+		assert!(tracker.poll().is_empty());
+		assert_eq!(tracker.poll(), vec![path]);
+	}
+
+	#[test]
+	fn removed_file_stops_waiting() {
+		let tmp = TempDir::new();
+		let path = tmp.0.join("a.txt");
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(0);
+		tracker.on_event(&created(&path));
+		fs::remove_file(&path).unwrap();
+		assert!(tracker.poll().is_empty());
+		assert!(tracker.pending_paths().is_empty());
+	}
+
+	#[test]
+	fn done_marker_makes_its_file_ready_at_once() {
+		let tmp = TempDir::new();
+		let (path, marker) = (tmp.0.join("a.txt"), tmp.0.join("a.txt.done"));
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(60);
+		tracker.on_event(&event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), &[&path]));
+		fs::write(&marker, "").unwrap();
+		assert_eq!(tracker.on_event(&created(&marker)), vec![path]);
+		assert!(tracker.pending_paths().is_empty());
+	}
+
+	#[test]
+	fn done_marker_without_its_file_is_ignored() {
+		let tmp = TempDir::new();
+		let marker = tmp.0.join("a.txt.done");
+		fs::write(&marker, "").unwrap();
+		let mut tracker = tracker(0);
+		assert!(tracker.on_event(&created(&marker)).is_empty());
+		assert!(tracker.pending_paths().is_empty());
+	}
+
+	#[test]
+	fn part_files_are_not_tracked() {
+		let tmp = TempDir::new();
+		let part = tmp.0.join("a.txt.part");
+		fs::write(&part, "one").unwrap();
+		let mut tracker = tracker(0);
+		assert!(tracker.on_event(&created(&part)).is_empty());
+		assert!(tracker.poll().is_empty());
+	}
+
+	#[test]
+	fn renaming_a_part_file_makes_the_target_ready() {
+		let tmp = TempDir::new();
+		let (part, path) = (tmp.0.join("a.txt.part"), tmp.0.join("a.txt"));
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(60);
+		assert_eq!(tracker.on_event(&renamed(&part, &path)), vec![path]);
+		assert!(tracker.pending_paths().is_empty());
+	}
+
+	#[test]
+	fn other_renames_wait_for_the_quiet_period() {
+		let tmp = TempDir::new();
+		let (from, path) = (tmp.0.join("b.txt"), tmp.0.join("a.txt"));
+		fs::write(&path, "one").unwrap();
+		let mut tracker = tracker(60);
+		assert!(tracker.on_event(&renamed(&from, &path)).is_empty());
+		assert_eq!(tracker.pending_paths(), HashSet::from([path]));
+	}
+
+	#[test]
+	fn poll_interval_is_a_quarter_of_the_quiet_period() {
+		assert_eq!(tracker(60).poll_interval(), Duration::from_secs(15));
+		assert_eq!(tracker(2).poll_interval(), Duration::from_secs(1));
+		assert_eq!(tracker(0).poll_interval(), Duration::from_secs(1));
+	}
+}
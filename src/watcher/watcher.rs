@@ -1,35 +1,45 @@
 use anyhow::{Ok,Result };
-use log::info;
-use log::warn;
-use redis::Commands;
+use log::{error,info,warn};
 use std::path::{Path,PathBuf };
 use notify::{Watcher, RecursiveMode, Event,EventKind,Result as NotifyResult};
-use std::sync::mpsc;
-use std::sync::Arc;
-use crate::model::book;
-use crate::processor::FileProcessor;
-use crate::config::RedisClient;
-use crate::model::book::{Book,BookRedisClient,PREFIX_QUEUE_BOOK_CDN,CHANNEL_PSB_BOOK_TASK};
+use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicBool,Ordering as AtomicOrdering};
+use std::sync::{Arc,Mutex};
+use crate::processor::{BookTask,FileProcessor,WorkerPool};
+use crate::processor::splitter::SplitOptions;
+use crate::model::book::{Book,CHANNEL_PSB_BOOK_TASK};
+use crate::model::store::BookStore;
+use crate::model::state::{log_state,now_secs,worker_id,JobStateClient,JobStatus};
+use crate::model::task::{BookRef,Pong,TaskMessage,TaskOp,TASK_MESSAGE_VERSION};
 use std::collections::HashSet;
-use tokio::time::{sleep, Duration};
+use tokio::time::{interval_at,sleep,Duration,Instant};
+use super::reconcile::reconcile;
+use super::rename::{RenameTracker,RENAME_WAIT};
+use super::upload::UploadTracker;
+use crate::config::{RetryPolicy,UploadConfig};
 use futures::stream::StreamExt;
-use std::result::Result::{Ok as ResultOk};
+use std::result::Result::Ok as ResultOk;
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct FileWatcher{
-	pub processor: FileProcessor,
+	pub processor: Arc<FileProcessor>,
+	pub pool: WorkerPool,
+	pub books: Arc<dyn BookStore>,
 	pub watcher: notify::RecommendedWatcher,
-	watcher_rx: mpsc::Receiver<NotifyResult<Event>>,
-	known_file: HashSet<PathBuf>,
+	watcher_rx: mpsc::UnboundedReceiver<NotifyResult<Event>>,
+	// sources seen by the last reconcile pass , and whether a pass is running
+	known_file: Arc<Mutex<HashSet<PathBuf>>>,
+	reconciling: Arc<AtomicBool>,
 	check_interval:Duration,
+	uploads: UploadTracker,
+	renames: RenameTracker,
  }
  
  impl FileWatcher{
-		pub fn new(input_dir:&str , output_dir:&str , redis_client:Arc<RedisClient>)->Result<Self>{
-				 let processor = FileProcessor::new(input_dir, output_dir, redis_client)?;
+		pub fn new(processor:Arc<FileProcessor>, pool:WorkerPool, books:Arc<dyn BookStore>)->Result<Self>{
 				 // create channel receive file-system-event
-				 let (tx,rx) = mpsc::channel();
+				 let (tx,rx) = mpsc::unbounded_channel();
 				 // create file watcher
 				 let mut watcher = notify::recommended_watcher(move|res|{
 						 if let Err(e) = tx.send(res){
@@ -37,63 +47,409 @@ pub struct FileWatcher{
 						 }
 				 })?;
 				 
-				 watcher.watch(Path::new(input_dir), RecursiveMode::Recursive)?;
+				 watcher.watch(&processor.input_dir, RecursiveMode::Recursive)?;
 				 Ok(Self{
 						 processor,
+						 pool,
+						 books,
 						 watcher,
 						 watcher_rx:rx,
-						 known_file: HashSet::new(),
+						 known_file: Arc::default(),
+						 reconciling: Arc::default(),
 						 check_interval: Duration::new(120, 0), // 120 second 0 nano
+						 uploads: UploadTracker::new(UploadConfig::default()),
+						 renames: RenameTracker::new(RENAME_WAIT),
 				 })
 		 }
  
+		// seconds between reconciliation passes
+		pub fn with_check_interval(mut self, check_interval:Duration)->Self{
+				self.check_interval = check_interval.max(Duration::from_secs(1));
+				self
+		}
+
+		pub fn with_upload(mut self, upload:UploadConfig)->Self{
+				self.uploads = UploadTracker::new(upload);
+				self
+		}
+
+		// runs until an `exit` task , a lost or failed subscription is set up again
+		// with backoff and followed by a reconciliation pass for tasks missed meanwhile
 		pub async  fn start_watching(&mut self)->Result<()>{
-			let conn = self.processor.redis_client.get_connection().await?;
-			let mut brclient = BookRedisClient::new(self.processor.redis_client.clone()).await?;
+			let mut outage:Option<Instant> = None;
+			let mut retries = 0;
+			loop{
+				match self.watch(&mut outage).await{
+					ResultOk(true) => return Ok(()),
+					ResultOk(false) => warn!("Task channel closed"),
+					Err(e) => warn!("Task channel err: {:?}",e),
+				}
+				if outage.is_none(){
+					error!("Lost task channel , reconnecting");
+					outage = Some(Instant::now());
+					retries = 0;
+				}
+				retries += 1;
+				let delay = RetryPolicy::RECONNECT.delay(retries);
+				warn!("Reconnect task channel in {:?} , attempt {}",delay,retries);
+				sleep(delay).await;
+			}
+		 }
+
+		// one subscription , true when an `exit` task ended it
+		async fn watch(&mut self, outage:&mut Option<Instant>)->Result<bool>{
+			let mut pubsub = self.processor.redis_client.get_pubsub().await?;
+			let mut state = JobStateClient::new(self.processor.redis_client.clone()).await?;
 
-			let mut pubsub = conn.into_pubsub();
-			pubsub.subscribe(CHANNEL_PSB_BOOK_TASK).await?;
+			pubsub.subscribe(self.processor.redis_client.keys().key(CHANNEL_PSB_BOOK_TASK)).await?;
+			if let Some(since) = outage.take(){
+				warn!("Task channel back after {:?} , reconciling",since.elapsed());
+				self.spawn_reconcile();
+			}
 			let mut  stream = pubsub.on_message();
-			while let Some(msg) = stream.next().await {
-				let payload:String = msg.get_payload()?;
-				if payload == "exit"{
-					break;
-				}
-				let book_id = payload.parse::<i32>().unwrap();
-				info!("Received message: {:?} , id :{:?}",payload,book_id);
-				let book = match brclient.get_book_by_id(&book_id).await? {
-					Some(book) => book,
-					None => {
-						warn!("Book not found: {:?}",&book_id);
+			let mut reconcile_tick = interval_at(Instant::now() + self.check_interval, self.check_interval);
+			let mut upload_tick = interval_at(Instant::now() + self.uploads.poll_interval(), self.uploads.poll_interval());
+			let mut rename_tick = interval_at(Instant::now() + self.renames.poll_interval(), self.renames.poll_interval());
+			loop {
+				let msg = tokio::select! {
+					msg = stream.next() => match msg{
+						Some(msg) => msg,
+						None => return Ok(false),
+					},
+					_ = reconcile_tick.tick() => {
+						self.spawn_reconcile();
+						continue;
+					}
+					_ = upload_tick.tick() => {
+						for path in self.uploads.poll(){
+							if let Err(e) = self.handle_upload(path.clone(),&mut state).await{
+								warn!("Upload {:?} err: {:?}",path,e);
+							}
+						}
+						continue;
+					}
+					_ = rename_tick.tick() => {
+						for path in self.renames.expired(){
+							self.source_gone(&path,&mut state).await;
+						}
+						continue;
+					}
+					event = self.watcher_rx.recv() => {
+						match event{
+							Some(ResultOk(event)) => self.handle_fs_event(event,&mut state).await,
+							Some(Err(e)) => warn!("File watch err: {:?}",e),
+							None => warn!("File watcher stopped"),
+						}
 						continue;
 					}
 				};
-				let file = match book.source_url{
-					Some(source) => source.split("/").collect::<Vec<&str>>().last().unwrap_or(&"").to_string(),
-					None => {
-						warn!("Book source not found: {:?}",&book_id);
+				// a bad message is dropped , it must never stop the watcher , neither does a
+				// failed task , event or upload , only a broken subscription ends `watch`
+				let payload:String = match msg.get_payload(){
+					ResultOk(payload) => payload,
+					Err(e) => {
+						warn!("Rejected task message , unreadable payload: {:?}",e);
 						continue;
 					}
 				};
-				let abpath = std::env::current_dir()?.join(&self.processor.input_dir).join(&file);
-				info!("abpath in watch start");
-				if abpath.exists(){ // source file exists
-							self.handle_new_file(&abpath,&file,book.start_count).await?;
-				}			
+				let task = match TaskMessage::parse(&payload){
+					ResultOk(task) => task,
+					Err(e) => {
+						warn!("Rejected task message {:?}: {:#}",payload,e);
+						continue;
+					}
+				};
+				info!("Received message: {:?}",task);
+				if task.op == TaskOp::Exit{
+					return Ok(true);
+				}
+				if let Err(e) = self.handle_task(task,&mut state).await{
+					warn!("Task err: {:?}",e);
+				}
 			}
+		 }
+
+		// a pass runs beside the task channel , it walks every book and may wait on a full
+		// pool , a tick that finds the previous pass still running is skipped
+		fn spawn_reconcile(&self){
+			if self.reconciling.swap(true,AtomicOrdering::AcqRel){
+				info!("Reconcile still running , pass skipped");
+				return;
+			}
+			let (processor,pool) = (self.processor.clone(),self.pool.clone());
+			let (known_file,reconciling) = (self.known_file.clone(),self.reconciling.clone());
+			let uploading = self.uploads.pending_paths();
+			tokio::spawn(async move{
+				let mut known = std::mem::take(&mut *known_file.lock().unwrap_or_else(|e| e.into_inner()));
+				if let Err(e) = reconcile(&processor,&pool,&mut known,&uploading).await{
+					warn!("Reconcile err: {:?}",e);
+				}
+				*known_file.lock().unwrap_or_else(|e| e.into_inner()) = known;
+				reconciling.store(false,AtomicOrdering::Release);
+			});
+		}
 
+		// removed and renamed sources update their book , written files wait in the
+		// upload tracker until complete
+		// the old path of a rename waits in the rename tracker for the new one , see `RenameTracker`
+		// a path that fails is logged , the others are still handled
+		async fn handle_fs_event(&mut self, event:Event, state:&mut JobStateClient){
+			if let EventKind::Remove(_) = event.kind{
+				for path in event.paths.iter(){
+					self.source_gone(path,state).await;
+				}
+			}
+			// a rename from the partial suffix completes an upload , it is no source rename
+			match self.renames.on_event(&event){
+				Some((from,to)) if !self.uploads.is_marker(&from) => {
+					match self.handle_source_renamed(&from,&to,state).await{
+						// its output was renamed along , the new name is no upload
+						ResultOk(true) => {
+							self.uploads.forget(&from);
+							self.uploads.forget(&to);
+							return;
+						}
+						ResultOk(false) => {}
+						Err(e) => warn!("Source {:?} renamed err: {:?}",from,e),
+					}
+				}
+				_ => {}
+			}
+			for path in self.uploads.on_event(&event){
+				if let Err(e) = self.handle_upload(path.clone(),state).await{
+					warn!("Upload {:?} err: {:?}",path,e);
+				}
+			}
+		}
+
+		async fn source_gone(&mut self, path:&Path, state:&mut JobStateClient){
+			self.uploads.forget(path);
+			if let Err(e) = self.handle_source_removed(path,state).await{
+				warn!("Source {:?} removed err: {:?}",path,e);
+			}
+		}
+
+		// the book of a removed source gets its output marked orphaned , and archived
+		// when an archive dir is set and no split of it is running
+		async fn handle_source_removed(&self, path:&Path, state:&mut JobStateClient)->Result<()>{
+			let file = match path.file_name().and_then(|n| n.to_str()){
+				Some(file) if !self.uploads.is_marker(path) => file.to_string(),
+				_ => return Ok(()),
+			};
+			let book_id = match self.books.get_by_source(&file).await?.and_then(|book| book.id){
+				Some(book_id) => book_id,
+				None => {
+					state.remove_unmatched(&file).await?;
+					return Ok(());
+				}
+			};
+			warn!("Source of book {:?} removed: {:?}",book_id,file);
+			let detail = if self.pool.is_running(book_id){
+				format!("{} , output kept , split running",file)
+			}else{
+				match self.processor.archive_output(&file){
+					ResultOk(Some(target)) => format!("{} , output archived to {}",file,target.display()),
+					ResultOk(None) => file.clone(),
+					Err(e) => {
+						warn!("Archive output of book {:?} err: {:?}",book_id,e);
+						format!("{} , archive failed: {:#}",file,e)
+					}
+				}
+			};
+			log_state(book_id, state.orphaned(book_id).await);
+			log_state(book_id, state.history(book_id,"source_removed",&detail).await);
 			Ok(())
-		 }
+		}
 
+		// the book follows its renamed source and its output is renamed along , true then
+		// false when the source is no book's or its output could not follow , a split
+		// running , no output yet or a failed rename , the renamed file is split like an upload
+		async fn handle_source_renamed(&self, from:&Path, to:&Path, state:&mut JobStateClient)->Result<bool>{
+			let (old, new) = match (from.file_name().and_then(|n| n.to_str()), to.file_name().and_then(|n| n.to_str())){
+				(Some(old), Some(new)) if old != new => (old.to_string(), new.to_string()),
+				_ => return Ok(false),
+			};
+			let book_id = match self.books.get_by_source(&old).await?.and_then(|book| book.id){
+				Some(book_id) => book_id,
+				None => return Ok(false),
+			};
+			info!("Source of book {:?} renamed: {:?} -> {:?}",book_id,old,new);
+			self.books.rename_source(book_id,&old,&new).await?;
+			let (detail, renamed) = if self.pool.is_running(book_id){
+				(format!("{} -> {} , split running , split again",old,new), false)
+			}else{
+				match self.processor.rename_output(&old,&new){
+					ResultOk(true) => (format!("{} -> {} , output renamed",old,new), true),
+					ResultOk(false) => (format!("{} -> {}",old,new), false),
+					Err(e) => {
+						warn!("Rename output of book {:?} err: {:?}",book_id,e);
+						(format!("{} -> {} , output rename failed: {:#} , split again",old,new,e), false)
+					}
+				}
+			};
+			log_state(book_id, state.history(book_id,"source_renamed",&detail).await);
+			Ok(renamed)
+		}
 
-		pub async  fn handle_new_file(&self, path: &Path,name:&str,stop:Option<i32>)->Result<()>{
-					info!("handleNewFile:{:?}",path);
-					if let Some(step) = stop {
-						// self.processor.process_with_retry(path).await?;
-						self.processor.process_file(path,name,step)?;
+		// a complete file in input_dir is split when a book points at it ,
+		// otherwise it is kept in the unmatched uploads set
+		async fn handle_upload(&self, path:PathBuf, state:&mut JobStateClient)->Result<()>{
+			if !path.is_file(){
+				return Ok(());
+			}
+			let file = match path.file_name().and_then(|n| n.to_str()){
+				Some(file) => file.to_string(),
+				None => return Ok(()),
+			};
+			match self.books.get_by_source(&file).await?{
+				Some(book) => {
+					state.remove_unmatched(&file).await?;
+					let book_id = match book.id{
+						Some(book_id) => book_id,
+						None => return Ok(()),
+					};
+					log_state(book_id, state.adopted(book_id).await);
+					match book.start_count{
+						Some(stop) => {
+							info!("Upload {:?} matches book {:?}",file,book_id);
+							self.handle_new_file(book_id,&book.uuid,&path,&file,SplitOptions::until(stop),book.priority()).await?;
+						}
+						None => warn!("Book has no start_count , skipped: {:?}",book_id),
+					}
+				}
+				None => {
+					info!("Upload {:?} matches no book",file);
+					state.add_unmatched(&file).await?;
+				}
+			}
+			Ok(())
+		}
+
+		async fn handle_task(&self, task:TaskMessage, state:&mut JobStateClient)->Result<()>{
+			let priority = task.priority;
+			match task.op{
+				TaskOp::Process{ book } => {
+					if let Some((book_id,book)) = self.books.find(&book).await?{
+						match book.start_count{
+							Some(stop) => self.submit_book(state,book_id,&book,SplitOptions::until(stop),priority).await?,
+							None => warn!("Book has no start_count , skipped: {:?}",book_id),
+						}
+					}
+				}
+				TaskOp::Reprocess{ book, force } => {
+					if let Some((book_id,book)) = self.books.find(&book).await?{
+						let split = SplitOptions{ overwrite:force, ..SplitOptions::until(book.start_count.unwrap_or(0)) };
+						self.submit_book(state,book_id,&book,split,priority).await?;
+					}
+				}
+				TaskOp::SplitRange{ book, from, to } => {
+					let stop = match i32::try_from(to){
+						ResultOk(stop) if from <= to => stop,
+						_ => {
+							warn!("Rejected split range {}..={}",from,to);
+							return Ok(());
+						}
+					};
+					if let Some((book_id,book)) = self.books.find(&book).await?{
+						self.submit_book(state,book_id,&book,SplitOptions{ from, stop, overwrite:true },priority).await?;
+					}
+				}
+				TaskOp::DeleteOutput{ book } => {
+					if let Some((book_id,book)) = self.books.find(&book).await?{
+						if self.pool.is_running(book_id){
+							warn!("Delete output ignored , book running: {:?}",book_id);
+							return Ok(());
+						}
+						match book.source_name().map(|file| self.processor.delete_output(&file)){
+							Some(ResultOk(true)) => info!("Deleted output of book {:?}",book_id),
+							Some(ResultOk(false)) => info!("Book has no output: {:?}",book_id),
+							Some(Err(e)) => warn!("Delete output of book {:?} err: {:?}",book_id,e),
+							None => warn!("Book source not found: {:?}",book_id),
+						}
+					}
+				}
+				TaskOp::Cancel{ book } => {
+					if let Some(book_id) = find_book_id(self.books.as_ref(),&book).await?{
+						if self.pool.cancel(book_id).await{
+							info!("Cancel requested: {:?}",book_id);
+						}else{
+							warn!("Cancel ignored , book not running or waiting: {:?}",book_id);
+						}
+					}
+				}
+				TaskOp::Ping{ reply_to } => {
+					let pong = Pong{ v:TASK_MESSAGE_VERSION, op:"pong", worker:worker_id(), running:self.pool.running_count(), at:now_secs() };
+					info!("Ping , {} books running",pong.running);
+					if let Some(channel) = reply_to{
+						if let Err(e) = pong.publish(self.processor.redis_client.clone(),&channel).await{
+							warn!("Reply to ping on {:?} err: {:?}",channel,e);
+						}
 					}
-				 println!("Processed file: {:?}", name);
-				 Ok(())
+				}
+				TaskOp::RequeueDead{ book } => {
+					let book_id = match book{
+						Some(book) => match find_book_id(self.books.as_ref(),&book).await?{
+							Some(book_id) => Some(book_id),
+							None => return Ok(()),
+						},
+						None => None,
+					};
+					// each letter waits on a full pool , the task channel must not
+					let pool = self.pool.clone();
+					tokio::spawn(async move{
+						match pool.requeue_dead(book_id).await{
+							ResultOk(n) => info!("Requeued {} dead letters",n),
+							Err(e) => warn!("Requeue dead letters err: {:?}",e),
+						}
+					});
+				}
+				TaskOp::Exit => {}
+			}
+			Ok(())
+		}
+
+		// split a book from its source file at the given priority or the book's own ,
+		// a missing source is recorded as a failed job
+		async fn submit_book(&self, state:&mut JobStateClient, book_id:i32, book:&Book, split:SplitOptions, priority:Option<i64>)->Result<()>{
+			let file = match book.source_name(){
+				Some(file) => file,
+				None => {
+					warn!("Book source not found: {:?}",&book_id);
+					log_state(book_id, state.finished(book_id,JobStatus::Failed,0,0,Some("book has no source_url")).await);
+					return Ok(());
+				}
+			};
+			let abpath = std::env::current_dir()?.join(&self.processor.input_dir).join(&file);
+			if abpath.exists(){ // source file exists
+				self.handle_new_file(book_id,&book.uuid,&abpath,&file,split,priority.unwrap_or_else(|| book.priority())).await?;
+			}else{
+				warn!("Book source file missing: {:?}",&abpath);
+				log_state(book_id, state.finished(book_id,JobStatus::Failed,0,0,Some("source file missing")).await);
+			}
+			Ok(())
+		}
+
+		// queue the file on the worker pool , past a full queue , the watcher loop
+		// also reads cancel , ping and exit messages and must not wait
+		pub async fn handle_new_file(&self, book_id:i32, uuid:&str, path: &Path,name:&str,split:SplitOptions,priority:i64)->Result<()>{
+					info!("handleNewFile:{:?}",path);
+					self.pool.submit_now(BookTask{
+						book_id,
+						uuid: uuid.to_string(),
+						path: path.to_path_buf(),
+						name: name.to_string(),
+						split,
+						priority,
+						done: None,
+					}).await?;
+					Ok(())
 		 }
  }
- 
+
+// an id is taken as is , so a book removed from redis can still be cancelled
+async fn find_book_id(books:&dyn BookStore, book:&BookRef)->Result<Option<i32>>{
+	match book{
+		BookRef::Id(id) => Ok(Some(*id)),
+		_ => Ok(books.find(book).await?.map(|(id,_)| id)),
+	}
+}